@@ -0,0 +1,299 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use crate::compile::{Lambda, Node, Op};
+use crate::env::Layout;
+use crate::eval::clone_err;
+use crate::symbol::Symbol;
+use crate::types::MalVal::{List, Nil};
+use crate::types::{MalErr, MalVal};
+
+// Bytecode for the stack VM in vm.rs. It is lowered from the analysed
+// node tree, so macro expansion, scoping and constant folding are the
+// same as for the tree evaluator. Each fn* body becomes a Proto of its
+// own; indexes in instructions refer to the tables of their Proto.
+
+#[derive(Clone, Copy, Debug)]
+pub enum Instr {
+    // push consts[i]
+    Const(usize),
+    // push the value of the symbol names[i]
+    Get(usize),
+    // push slot s of the env d levels out
+    Local(usize, usize),
+    // set names[i] to the top of the stack, leaving it there
+    Def(usize),
+    DefMacro(usize),
+    // pop the top of the stack into slot s of the current env
+    SetLocal(usize),
+    Pop,
+    Jump(usize),
+    // pop, and jump if it was false or nil
+    JumpIfFalse(usize),
+    // pop n values into a vector
+    Vector(usize),
+    // pop a value for each of keys[i] into a hash-map
+    Hash(usize),
+    // push a closure of protos[i] over the current env
+    Closure(usize),
+    // if the function on top of the stack is a macro, expand the form
+    // consts[i] with it, run the expansion and jump to the target
+    MacroCheck(usize, usize),
+    // call the function below the top n values with them as arguments;
+    // consts[i] is the form, for tracing
+    Call(usize, usize),
+    TailCall(usize, usize),
+    // start and end the env of a let* or catch*, laid out by layouts[i]
+    EnterScope(usize),
+    LeaveScope,
+    // until the matching EndTry, errors jump to the target with the
+    // exception pushed
+    Try(usize),
+    EndTry,
+    // raise errors[i]
+    Raise(usize),
+    // print consts[i] if DEBUG-EVAL is set
+    Trace(usize),
+    // count a run of the coverage site
+    Cover(usize),
+    Return,
+}
+
+pub struct Proto {
+    pub code: Vec<Instr>,
+    pub consts: Vec<MalVal>,
+    pub names: Vec<Symbol>,
+    pub keys: Vec<Vec<String>>,
+    pub protos: Vec<Rc<Proto>>,
+    pub errors: Vec<MalErr>,
+    pub layouts: Vec<Layout>,
+    // for the closures made from this Proto
+    pub params: Rc<MalVal>,
+    pub ast: Rc<MalVal>,
+    pub layout: Layout,
+    pub variadic: bool,
+    pub name: Cell<Option<Symbol>>,
+}
+
+// Lowers a top-level form, which runs in the env it is evaluated in.
+pub fn lower(form: &Node) -> Rc<Proto> {
+    let ast = Rc::new(form.form.clone());
+    lower_body(form, Rc::new(list![]), ast, Rc::new(vec![]), false)
+}
+
+fn lower_body(
+    body: &Node,
+    params: Rc<MalVal>,
+    ast: Rc<MalVal>,
+    layout: Layout,
+    variadic: bool,
+) -> Rc<Proto> {
+    let mut p = Proto {
+        code: vec![],
+        consts: vec![],
+        names: vec![],
+        keys: vec![],
+        protos: vec![],
+        errors: vec![],
+        layouts: vec![],
+        params,
+        ast,
+        layout,
+        variadic,
+        name: Cell::new(None),
+    };
+    p.trace(body);
+    p.emit(body, true);
+    Rc::new(p)
+}
+
+fn lower_lambda(l: &Lambda) -> Rc<Proto> {
+    lower_body(
+        &l.body,
+        l.params.clone(),
+        l.ast.clone(),
+        l.layout.clone(),
+        l.variadic,
+    )
+}
+
+impl Proto {
+    fn push(&mut self, i: Instr) {
+        self.code.push(i)
+    }
+
+    fn konst(&mut self, v: &MalVal) -> usize {
+        self.consts.push(v.clone());
+        self.consts.len() - 1
+    }
+
+    fn enter(&mut self, layout: &Layout) {
+        self.layouts.push(layout.clone());
+        let k = self.layouts.len() - 1;
+        self.push(Instr::EnterScope(k))
+    }
+
+    fn name(&mut self, s: Symbol) -> usize {
+        match self.names.iter().position(|n| *n == s) {
+            Some(i) => i,
+            None => {
+                self.names.push(s);
+                self.names.len() - 1
+            }
+        }
+    }
+
+    // Points the jump at `at` to the next instruction.
+    fn patch(&mut self, at: usize) {
+        let target = self.code.len();
+        match self.code[at] {
+            Instr::Jump(ref mut t)
+            | Instr::JumpIfFalse(ref mut t)
+            | Instr::Try(ref mut t)
+            | Instr::MacroCheck(_, ref mut t) => *t = target,
+            _ => unreachable!("patching a non-jump"),
+        }
+    }
+
+    // DEBUG-EVAL is reported where the tree evaluator goes round its
+    // loop: on entry and for the forms that a special form continues
+    // with.
+    fn trace(&mut self, node: &Node) {
+        let k = self.konst(&node.form);
+        self.push(Instr::Trace(k))
+    }
+
+    // Emits code that leaves the value of node on the stack, or in tail
+    // position returns it from the Proto.
+    fn emit(&mut self, node: &Node, tail: bool) {
+        match &node.op {
+            Op::Const(v) => {
+                let k = self.konst(v);
+                self.push(Instr::Const(k))
+            }
+            Op::Sym(s) => {
+                let n = self.name(*s);
+                self.push(Instr::Get(n))
+            }
+            Op::Local(depth, slot) => self.push(Instr::Local(*depth, *slot)),
+            Op::Vector(items) => {
+                for n in items.iter() {
+                    self.emit(n, false)
+                }
+                self.push(Instr::Vector(items.len()))
+            }
+            Op::Hash(entries) => {
+                for (_, n) in entries.iter() {
+                    self.emit(n, false)
+                }
+                self.keys
+                    .push(entries.iter().map(|(k, _)| k.to_string()).collect());
+                let k = self.keys.len() - 1;
+                self.push(Instr::Hash(k))
+            }
+            Op::Def(name, val) => {
+                self.emit(val, false);
+                let n = self.name(*name);
+                self.push(Instr::Def(n))
+            }
+            Op::DefMacro(name, val) => {
+                self.emit(val, false);
+                let n = self.name(*name);
+                self.push(Instr::DefMacro(n))
+            }
+            Op::Let(layout, bindings, body) => {
+                self.enter(layout);
+                for (slot, val) in bindings.iter() {
+                    self.emit(val, false);
+                    self.push(Instr::SetLocal(*slot))
+                }
+                self.trace(body);
+                self.emit(body, tail);
+                if !tail {
+                    self.push(Instr::LeaveScope)
+                }
+                return;
+            }
+            Op::Do(forms, last) => {
+                for n in forms.iter() {
+                    self.emit(n, false);
+                    self.push(Instr::Pop)
+                }
+                self.trace(last);
+                self.emit(last, tail);
+                return;
+            }
+            Op::If(cond, then, otherwise) => {
+                self.emit(cond, false);
+                let jf = self.code.len();
+                self.push(Instr::JumpIfFalse(0));
+                self.trace(then);
+                self.emit(then, tail);
+                let j = self.code.len();
+                if !tail {
+                    self.push(Instr::Jump(0))
+                }
+                self.patch(jf);
+                self.trace(otherwise);
+                self.emit(otherwise, tail);
+                if !tail {
+                    self.patch(j)
+                }
+                return;
+            }
+            Op::Fn(lambda) => {
+                self.protos.push(lower_lambda(lambda));
+                let k = self.protos.len() - 1;
+                self.push(Instr::Closure(k))
+            }
+            Op::Try(body, layout, handler) => {
+                // the body is never in tail position, so that the
+                // handler stays installed while it runs
+                let t = self.code.len();
+                self.push(Instr::Try(0));
+                self.emit(body, false);
+                self.push(Instr::EndTry);
+                let j = self.code.len();
+                self.push(Instr::Jump(0));
+                self.patch(t);
+                self.enter(layout);
+                self.push(Instr::SetLocal(0));
+                self.trace(handler);
+                self.emit(handler, tail);
+                if !tail {
+                    self.push(Instr::LeaveScope)
+                }
+                self.patch(j);
+            }
+            Op::Call(f, args) => {
+                self.emit(f, false);
+                let m = self.code.len();
+                let k = self.konst(&node.form);
+                self.push(Instr::MacroCheck(k, 0));
+                for n in args.iter() {
+                    self.emit(n, false)
+                }
+                if tail {
+                    self.push(Instr::TailCall(args.len(), k))
+                } else {
+                    self.push(Instr::Call(args.len(), k))
+                }
+                // a macro expansion continues here
+                self.patch(m);
+            }
+            Op::Cover(site, n) => {
+                self.push(Instr::Cover(*site));
+                self.emit(n, tail);
+                return;
+            }
+            Op::Error(e) => {
+                self.errors.push(clone_err(e));
+                let k = self.errors.len() - 1;
+                self.push(Instr::Raise(k))
+            }
+        }
+        if tail {
+            self.push(Instr::Return)
+        }
+    }
+}
@@ -0,0 +1,395 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use fnv::FnvHashMap;
+use itertools::Itertools;
+
+use crate::cover;
+use crate::env::{env_get, Env, Layout};
+use crate::symbol::intern;
+use crate::symbol::Symbol;
+use crate::trace;
+use crate::types::MalErr::ErrString;
+use crate::types::MalVal::{Bool, Hash, List, MalFunc, Nil, Sym, Vector};
+use crate::types::{new_hash, MalArgs, MalErr, MalVal};
+
+// The analysis pass: each form is converted once into a tree of nodes
+// with the special forms already recognised, macros expanded and
+// constant subexpressions folded, so that running it never has to
+// look at the form again. The form is kept on every node for error
+// reporting and debugging.
+
+pub struct Node {
+    pub form: MalVal,
+    pub op: Op,
+}
+
+pub enum Op {
+    Const(MalVal),
+    // a variable that no enclosing fn*, let* or catch* binds
+    Sym(Symbol),
+    // slot of the env `depth` levels out
+    Local(usize, usize),
+    Vector(Vec<Rc<Node>>),
+    Hash(Vec<(String, Rc<Node>)>),
+    Def(Symbol, Rc<Node>),
+    DefMacro(Symbol, Rc<Node>),
+    Let(Layout, Vec<(usize, Rc<Node>)>, Rc<Node>),
+    Do(Vec<Rc<Node>>, Rc<Node>),
+    If(Rc<Node>, Rc<Node>, Rc<Node>),
+    Fn(Rc<Lambda>),
+    Try(Rc<Node>, Layout, Rc<Node>),
+    Call(Rc<Node>, Vec<Rc<Node>>),
+    // raised when the node runs, so that e.g. try* can still catch
+    // a malformed form or a failing macro inside it
+    Error(MalErr),
+    // count a run of the coverage site, then run the node
+    Cover(usize, Rc<Node>),
+}
+
+pub struct Lambda {
+    pub params: Rc<MalVal>,
+    pub ast: Rc<MalVal>,
+    pub layout: Layout,
+    pub variadic: bool,
+    pub body: Rc<Node>,
+    pub name: Cell<Option<Symbol>>,
+}
+
+// The fn*, let* and catch* forms being compiled, innermost last, each
+// of which makes one env at run time. Their names shadow any macro of
+// the same name.
+#[derive(Default)]
+pub struct Scope {
+    frames: Vec<Frame>,
+}
+
+struct Frame {
+    // the names with a slot, in slot order
+    slots: Vec<Symbol>,
+    // names def! creates in the env, which are looked up by name
+    defs: Vec<Symbol>,
+}
+
+impl Scope {
+    fn is_local(&self, name: Symbol) -> bool {
+        self.frames
+            .iter()
+            .any(|f| f.slots.iter().chain(f.defs.iter()).any(|n| *n == name))
+    }
+
+    // The (depth, slot) of a name, or None if it has to be looked up by
+    // name at run time.
+    fn resolve(&self, name: Symbol) -> Option<(usize, usize)> {
+        for (depth, f) in self.frames.iter().rev().enumerate() {
+            if f.defs.contains(&name) {
+                return None;
+            }
+            if let Some(slot) = f.slots.iter().position(|n| *n == name) {
+                return Some((depth, slot));
+            }
+        }
+        None
+    }
+
+    fn push(&mut self, slots: Vec<Symbol>) {
+        slots.iter().for_each(|s| trace::note_binding(*s));
+        self.frames.push(Frame {
+            slots,
+            defs: vec![],
+        })
+    }
+
+    fn pop(&mut self) -> Layout {
+        let f = self.frames.pop().expect("unbalanced scope");
+        Rc::new(f.slots)
+    }
+
+    fn bind(&mut self, name: Symbol) {
+        trace::note_binding(name);
+        if let Some(f) = self.frames.last_mut() {
+            f.defs.push(name);
+        }
+    }
+
+    // Gives name the next slot of the innermost frame, unless it
+    // already has one.
+    fn bind_local(&mut self, name: Symbol) -> usize {
+        trace::note_binding(name);
+        let f = self.frames.last_mut().expect("no frame to bind in");
+        match f.slots.iter().position(|n| *n == name) {
+            Some(slot) => slot,
+            None => {
+                f.slots.push(name);
+                f.slots.len() - 1
+            }
+        }
+    }
+}
+
+fn qq_iter(elts: &MalArgs) -> MalVal {
+    let mut acc = list![];
+    for elt in elts.iter().rev() {
+        if let List(v, _) = elt {
+            if v.len() == 2 {
+                if let Sym(ref s) = v[0] {
+                    if s == "splice-unquote" {
+                        acc = list![Sym(intern("concat")), v[1].clone(), acc];
+                        continue;
+                    }
+                }
+            }
+        }
+        acc = list![Sym(intern("cons")), quasiquote(elt), acc];
+    }
+    acc
+}
+
+fn quasiquote(ast: &MalVal) -> MalVal {
+    match ast {
+        List(v, _) => {
+            if v.len() == 2 {
+                if let Sym(ref s) = v[0] {
+                    if s == "unquote" {
+                        return v[1].clone();
+                    }
+                }
+            }
+            qq_iter(v)
+        }
+        Vector(v, _) => list![Sym(intern("vec")), qq_iter(v)],
+        Hash(_, _) | Sym(_) => list![Sym(intern("quote")), ast.clone()],
+        _ => ast.clone(),
+    }
+}
+
+fn node(form: &MalVal, op: Op) -> Rc<Node> {
+    Rc::new(Node {
+        form: form.clone(),
+        op,
+    })
+}
+
+fn fail(form: &MalVal, msg: &str) -> Rc<Node> {
+    node(form, Op::Error(ErrString(msg.to_string())))
+}
+
+fn const_val(n: &Node) -> Option<&MalVal> {
+    match n.op {
+        Op::Const(ref v) => Some(v),
+        _ => None,
+    }
+}
+
+// The names of the params in slot order, and whether the last one
+// collects the remaining args.
+fn param_names(params: &MalVal) -> Option<(Vec<Symbol>, bool)> {
+    let mut names: Vec<Symbol> = match params {
+        List(ps, _) | Vector(ps, _) => ps.iter().map(sym_name).collect::<Option<_>>()?,
+        _ => return None,
+    };
+    match names.iter().position(|n| *n == "&") {
+        Some(i) if i + 1 < names.len() => {
+            names.truncate(i + 2);
+            names.remove(i);
+            Some((names, true))
+        }
+        Some(i) => {
+            names.truncate(i);
+            Some((names, false))
+        }
+        None => Some((names, false)),
+    }
+}
+
+fn sym_name(form: &MalVal) -> Option<Symbol> {
+    match form {
+        Sym(s) => Some(*s),
+        _ => None,
+    }
+}
+
+pub fn compile(ast: &MalVal, env: &Env) -> Rc<Node> {
+    analyze(ast, env, &mut Scope::default())
+}
+
+pub fn analyze(ast: &MalVal, env: &Env, scope: &mut Scope) -> Rc<Node> {
+    match ast {
+        Sym(s) => match scope.resolve(*s) {
+            Some((depth, slot)) => node(ast, Op::Local(depth, slot)),
+            None => node(ast, Op::Sym(*s)),
+        },
+        Vector(v, _) => {
+            let items: Vec<Rc<Node>> = v.iter().map(|a| analyze(a, env, scope)).collect();
+            match items.iter().map(|n| const_val(n).cloned()).collect() {
+                Some(vals) => node(ast, Op::Const(vector!(vals))),
+                None => node(ast, Op::Vector(items)),
+            }
+        }
+        Hash(hm, _) => {
+            let entries: Vec<(String, Rc<Node>)> = hm
+                .iter()
+                .map(|(k, v)| (k.to_string(), analyze(v, env, scope)))
+                .collect();
+            let vals: Option<FnvHashMap<String, MalVal>> = entries
+                .iter()
+                .map(|(k, n)| const_val(n).map(|v| (k.to_string(), v.clone())))
+                .collect();
+            match vals {
+                Some(hm) => node(ast, Op::Const(new_hash(hm))),
+                None => node(ast, Op::Hash(entries)),
+            }
+        }
+        List(l, _) if !l.is_empty() => {
+            let site = if cover::enabled() { cover::site(ast) } else { None };
+            let n = cover::within(site, || analyze_list(ast, l, env, scope));
+            match site {
+                Some(site) if const_val(&n).is_none() => {
+                    cover::compiled(site);
+                    node(ast, Op::Cover(site, n))
+                }
+                _ => n,
+            }
+        }
+        _ => node(ast, Op::Const(ast.clone())),
+    }
+}
+
+fn analyze_list(ast: &MalVal, l: &[MalVal], env: &Env, scope: &mut Scope) -> Rc<Node> {
+    let a0sym = match l[0] {
+        Sym(s) => s.name(),
+        _ => "",
+    };
+    match a0sym {
+        "def!" | "defmacro!" => {
+            let name = match (l.get(1).and_then(sym_name), l.get(2)) {
+                (Some(name), Some(_)) => name,
+                (None, Some(_)) => return fail(ast, "Env.set called with non-Str"),
+                _ => return fail(ast, &format!("{} requires a name and a value", a0sym)),
+            };
+            let val = analyze(&l[2], env, scope);
+            scope.bind(name);
+            if a0sym == "def!" {
+                node(ast, Op::Def(name, val))
+            } else {
+                node(ast, Op::DefMacro(name, val))
+            }
+        }
+        "let*" => {
+            let binds = match l.get(1) {
+                Some(List(binds, _)) | Some(Vector(binds, _)) if l.len() > 2 => binds,
+                _ => return fail(ast, "let* with non-List bindings"),
+            };
+            scope.push(vec![]);
+            let mut bindings = vec![];
+            for (b, e) in binds.iter().tuples() {
+                let name = match sym_name(b) {
+                    Some(name) => name,
+                    None => {
+                        scope.pop();
+                        return fail(ast, "Env.set called with non-Str");
+                    }
+                };
+                let val = analyze(e, env, scope);
+                bindings.push((scope.bind_local(name), val));
+            }
+            let body = analyze(&l[2], env, scope);
+            let layout = scope.pop();
+            node(ast, Op::Let(layout, bindings, body))
+        }
+        "quote" => node(ast, Op::Const(l.get(1).cloned().unwrap_or(Nil))),
+        "quasiquote" => match l.get(1) {
+            Some(a1) => analyze(&quasiquote(a1), env, scope),
+            None => node(ast, Op::Const(Nil)),
+        },
+        "try*" => {
+            let body = match l.get(1) {
+                Some(a1) => analyze(a1, env, scope),
+                None => node(ast, Op::Const(Nil)),
+            };
+            if l.len() < 3 {
+                return body;
+            }
+            let (name, handler) = match &l[2] {
+                List(c, _) if c.len() == 3 => match sym_name(&c[1]) {
+                    Some(name) => (name, &c[2]),
+                    None => return fail(ast, "invalid catch block"),
+                },
+                _ => return fail(ast, "invalid catch block"),
+            };
+            scope.push(vec![name]);
+            let handler = analyze(handler, env, scope);
+            let layout = scope.pop();
+            node(ast, Op::Try(body, layout, handler))
+        }
+        "do" => {
+            let mut forms: Vec<Rc<Node>> = l[1..].iter().map(|a| analyze(a, env, scope)).collect();
+            let last = forms.pop().unwrap_or_else(|| node(ast, Op::Const(Nil)));
+            // constants before the last form have no effect
+            forms.retain(|n| const_val(n).is_none());
+            if forms.is_empty() {
+                last
+            } else {
+                node(ast, Op::Do(forms, last))
+            }
+        }
+        "if" => {
+            let (cond, then) = match (l.get(1), l.get(2)) {
+                (Some(a1), Some(a2)) => (analyze(a1, env, scope), analyze(a2, env, scope)),
+                _ => return fail(ast, "if requires a condition and a branch"),
+            };
+            let otherwise = match l.get(3) {
+                Some(a3) => analyze(a3, env, scope),
+                None => node(&Nil, Op::Const(Nil)),
+            };
+            match const_val(&cond) {
+                Some(Bool(false)) | Some(Nil) => otherwise,
+                Some(_) => then,
+                None => match cover::branches() {
+                    Some((t, f)) => {
+                        let then = node(&then.form, Op::Cover(t, then.clone()));
+                        let otherwise = node(&otherwise.form, Op::Cover(f, otherwise.clone()));
+                        node(ast, Op::If(cond, then, otherwise))
+                    }
+                    None => node(ast, Op::If(cond, then, otherwise)),
+                },
+            }
+        }
+        "fn*" => {
+            let (params, body) = match (l.get(1), l.get(2)) {
+                (Some(p), Some(b)) => (p, b),
+                _ => return fail(ast, "fn* requires a parameter list and a body"),
+            };
+            let (names, variadic) = match param_names(params) {
+                Some(names) => names,
+                None => return fail(ast, "fn* parameters must be a list of symbols"),
+            };
+            scope.push(names);
+            let body_node = analyze(body, env, scope);
+            let lambda = Lambda {
+                params: Rc::new(params.clone()),
+                ast: Rc::new(body.clone()),
+                layout: scope.pop(),
+                variadic,
+                body: body_node,
+                name: Cell::new(None),
+            };
+            node(ast, Op::Fn(Rc::new(lambda)))
+        }
+        _ => {
+            if let Sym(s) = l[0] {
+                if !scope.is_local(s) {
+                    if let Some(f @ MalFunc { is_macro: true, .. }) = env_get(env, s) {
+                        return match f.apply(l[1..].to_vec()) {
+                            Ok(expanded) => analyze(&expanded, env, scope),
+                            Err(e) => node(ast, Op::Error(e)),
+                        };
+                    }
+                }
+            }
+            let f = analyze(&l[0], env, scope);
+            let args = l[1..].iter().map(|a| analyze(a, env, scope)).collect();
+            node(ast, Op::Call(f, args))
+        }
+    }
+}
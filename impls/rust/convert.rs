@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::hash::BuildHasher;
+use std::rc::Rc;
+
+use fnv::FnvHashMap;
+
+use crate::types::MalErr::ErrString;
+use crate::types::MalVal::{Bool, Hash, Int, Kw, List, Nil, Str, Vector};
+use crate::types::{builtin, key_val, keyword, new_hash, Arity, MalArgs, MalErr, MalRet, MalVal};
+
+// Conversions between Rust values and MalVal, so that embedders can
+// pass typed values in and out of the interpreter and register plain
+// Rust functions as builtins.
+
+pub trait FromMal: Sized {
+    fn from_mal(v: &MalVal) -> Result<Self, MalErr>;
+}
+
+// Fails for values mal has no room for, e.g. integers over i64::MAX
+pub trait IntoMal {
+    fn into_mal(self) -> MalRet;
+}
+
+fn expected<T>(what: &str, v: &MalVal) -> Result<T, MalErr> {
+    Err(ErrString(format!(
+        "expected {}, got {}",
+        what,
+        v.pr_str(true)
+    )))
+}
+
+impl FromMal for MalVal {
+    fn from_mal(v: &MalVal) -> Result<Self, MalErr> {
+        Ok(v.clone())
+    }
+}
+
+impl IntoMal for MalVal {
+    fn into_mal(self) -> MalRet {
+        Ok(self)
+    }
+}
+
+impl FromMal for bool {
+    fn from_mal(v: &MalVal) -> Result<Self, MalErr> {
+        match v {
+            Bool(b) => Ok(*b),
+            _ => expected("boolean", v),
+        }
+    }
+}
+
+impl IntoMal for bool {
+    fn into_mal(self) -> MalRet {
+        Ok(Bool(self))
+    }
+}
+
+macro_rules! int_conversions {
+    ($($t:ty),*) => {$(
+        impl FromMal for $t {
+            fn from_mal(v: &MalVal) -> Result<Self, MalErr> {
+                match v {
+                    Int(i) => <$t>::try_from(*i).or_else(|_| {
+                        expected(concat!("int in range of ", stringify!($t)), v)
+                    }),
+                    _ => expected("int", v),
+                }
+            }
+        }
+
+        impl IntoMal for $t {
+            fn into_mal(self) -> MalRet {
+                i64::try_from(self)
+                    .map(Int)
+                    .map_err(|_| ErrString(format!("{} is too big for an int", self)))
+            }
+        }
+    )*};
+}
+
+int_conversions!(i64, i32, i16, i8, u32, u16, u8, usize);
+
+// Keywords are not strings on the Rust side.
+impl FromMal for String {
+    fn from_mal(v: &MalVal) -> Result<Self, MalErr> {
+        match v {
+            Str(s) => Ok(s.to_string()),
+            _ => expected("string", v),
+        }
+    }
+}
+
+impl IntoMal for String {
+    fn into_mal(self) -> MalRet {
+        Ok(Str(self))
+    }
+}
+
+impl IntoMal for &str {
+    fn into_mal(self) -> MalRet {
+        Ok(Str(self.to_string()))
+    }
+}
+
+impl IntoMal for () {
+    fn into_mal(self) -> MalRet {
+        Ok(Nil)
+    }
+}
+
+impl<T: FromMal> FromMal for Option<T> {
+    fn from_mal(v: &MalVal) -> Result<Self, MalErr> {
+        match v {
+            Nil => Ok(None),
+            _ => Ok(Some(T::from_mal(v)?)),
+        }
+    }
+}
+
+impl<T: IntoMal> IntoMal for Option<T> {
+    fn into_mal(self) -> MalRet {
+        match self {
+            Some(v) => v.into_mal(),
+            None => Ok(Nil),
+        }
+    }
+}
+
+impl<T: FromMal> FromMal for Vec<T> {
+    fn from_mal(v: &MalVal) -> Result<Self, MalErr> {
+        match v {
+            List(l, _) | Vector(l, _) => l.iter().map(T::from_mal).collect(),
+            Nil => Ok(vec![]),
+            _ => expected("list or vector", v),
+        }
+    }
+}
+
+// Vecs become vectors rather than lists, since they are data.
+impl<T: IntoMal> IntoMal for Vec<T> {
+    fn into_mal(self) -> MalRet {
+        Ok(vector!(self
+            .into_iter()
+            .map(IntoMal::into_mal)
+            .collect::<Result<_, _>>()?))
+    }
+}
+
+// Map keys may be strings or keywords for a Rust map; either way the
+// Rust key is the bare name, so a map may not have both for one name.
+impl<T: FromMal, S: BuildHasher + Default> FromMal for HashMap<String, T, S> {
+    fn from_mal(v: &MalVal) -> Result<Self, MalErr> {
+        match v {
+            Hash(hm, _) => {
+                let mut res = HashMap::default();
+                for (k, v) in hm.iter() {
+                    let name = match key_val(k) {
+                        Str(name) => name,
+                        Kw(name) => name.to_string(),
+                        k => return expected("string or keyword key", &k),
+                    };
+                    // only a string and a keyword can have the same name
+                    if res.contains_key(&name) {
+                        return Err(ErrString(format!(
+                            "keys {} and {} are the same",
+                            Str(name.clone()).pr_str(true),
+                            keyword(&name).pr_str(true)
+                        )));
+                    }
+                    res.insert(name, T::from_mal(v)?);
+                }
+                Ok(res)
+            }
+            Nil => Ok(HashMap::default()),
+            _ => expected("hash-map", v),
+        }
+    }
+}
+
+impl<T: IntoMal, S: BuildHasher> IntoMal for HashMap<String, T, S> {
+    fn into_mal(self) -> MalRet {
+        let hm: FnvHashMap<String, MalVal> = self
+            .into_iter()
+            .map(|(k, v)| Ok((k, v.into_mal()?)))
+            .collect::<Result<_, MalErr>>()?;
+        Ok(new_hash(hm))
+    }
+}
+
+macro_rules! tuple_conversions {
+    ($len:expr; $($t:ident $i:tt),*) => {
+        impl<$($t: FromMal),*> FromMal for ($($t,)*) {
+            fn from_mal(v: &MalVal) -> Result<Self, MalErr> {
+                match v {
+                    List(l, _) | Vector(l, _) if l.len() == $len => {
+                        Ok(($($t::from_mal(&l[$i])?,)*))
+                    }
+                    _ => expected(concat!("sequence of length ", $len), v),
+                }
+            }
+        }
+
+        impl<$($t: IntoMal),*> IntoMal for ($($t,)*) {
+            fn into_mal(self) -> MalRet {
+                Ok(vector!(vec![$(self.$i.into_mal()?),*]))
+            }
+        }
+    };
+}
+
+tuple_conversions!(1; A 0);
+tuple_conversions!(2; A 0, B 1);
+tuple_conversions!(3; A 0, B 1, C 2);
+tuple_conversions!(4; A 0, B 1, C 2, D 3);
+
+// Return values of typed builtins: either a value, or a Result whose
+// error is reported as a mal exception.
+pub trait IntoMalRet {
+    fn into_mal_ret(self) -> MalRet;
+}
+
+impl<T: IntoMal> IntoMalRet for T {
+    fn into_mal_ret(self) -> MalRet {
+        self.into_mal()
+    }
+}
+
+impl<T: IntoMal, E: Display> IntoMalRet for Result<T, E> {
+    fn into_mal_ret(self) -> MalRet {
+        match self {
+            Ok(v) => v.into_mal(),
+            Err(e) => Err(ErrString(e.to_string())),
+        }
+    }
+}
+
+fn arg<T: FromMal>(name: &str, args: &MalArgs, i: usize) -> Result<T, MalErr> {
+    T::from_mal(&args[i]).map_err(|e| ErrString(format!("{}: argument {}: {}", name, i + 1, e)))
+}
+
+// Rust functions and closures whose arguments implement FromMal and
+// whose result implements IntoMalRet. Args is the tuple of argument
+// types, only there to tell the implementations apart.
+pub trait TypedFn<Args> {
+    fn arity(&self) -> usize;
+    fn call_typed(&self, name: &str, args: MalArgs) -> MalRet;
+}
+
+macro_rules! typed_fn {
+    ($($t:ident),*) => {
+        impl<F, R, $($t),*> TypedFn<($($t,)*)> for F
+        where
+            F: Fn($($t),*) -> R,
+            R: IntoMalRet,
+            $($t: FromMal,)*
+        {
+            fn arity(&self) -> usize {
+                let names: &[&str] = &[$(stringify!($t)),*];
+                names.len()
+            }
+
+            #[allow(unused_mut, unused_variables)]
+            fn call_typed(&self, name: &str, args: MalArgs) -> MalRet {
+                let mut i = 0;
+                (self)($({
+                    i += 1;
+                    arg::<$t>(name, &args, i - 1)?
+                }),*)
+                .into_mal_ret()
+            }
+        }
+    };
+}
+
+typed_fn!();
+typed_fn!(A);
+typed_fn!(A, B);
+typed_fn!(A, B, C);
+typed_fn!(A, B, C, D);
+typed_fn!(A, B, C, D, E);
+typed_fn!(A, B, C, D, E, G);
+
+// Wraps a typed Rust function as a builtin; the arity comes from the
+// function's signature and each argument is converted before the call.
+pub fn typed_builtin<Args, F>(name: &str, f: F) -> MalVal
+where
+    F: TypedFn<Args> + 'static,
+{
+    let arity = Arity::Exactly(f.arity());
+    let fname = name.to_string();
+    builtin(name, arity, move |args| f.call_typed(&fname, args))
+}
@@ -1,27 +1,34 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::fs::File;
 use std::io::Read;
 use std::rc::Rc;
-use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use itertools::Itertools;
+
 extern crate rustyline;
 use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
 use rustyline::Editor;
 
+use fnv::FnvHashMap;
+
+use crate::edn::read_edn;
+use crate::env::{env_entries, env_find_repl, env_get};
+use crate::limits::{check_size, sized};
 use crate::printer::pr_seq;
 use crate::reader::read_str;
-use crate::types::MalErr::ErrMalVal;
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{MalArgs, MalRet, MalVal, _assoc, _dissoc, atom, error, func, hash_map};
-
-macro_rules! fn_t_int_int {
-    ($ret:ident, $fn:expr) => {{
-        |a: MalArgs| match (&a[0], &a[1]) {
-            (Int(a0), Int(a1)) => Ok($ret($fn(a0, a1))),
-            _ => error("expecting (int,int) args"),
-        }
-    }};
-}
+use crate::types::Arity::{AtLeast, Between, Exactly};
+use crate::types::MalErr::{ErrMalVal, ErrString};
+use crate::types::MalVal::{
+    Atom, Bool, Func, Hash, Int, Kw, List, MalFunc, Nil, SortedMap, SortedSet, Str, Sym, Vector,
+};
+use crate::types::{
+    Arity, Context, MalArgs, MalErr, MalRet, MalVal, _assoc, _dissoc, _sorted_assoc,
+    _sorted_conj, _sorted_disj, _sorted_dissoc, atom, builtin, builtin_ctx, compare_with, error,
+    hash_key, hash_map, key_val, keyword, new_hash, sorted_map, sorted_set,
+};
 
 macro_rules! fn_is_type {
   ($($ps:pat),*) => {{
@@ -44,50 +51,104 @@ macro_rules! fn_str {
     }};
 }
 
+fn ints(a: &MalArgs) -> Result<Vec<i64>, MalErr> {
+    a.iter()
+        .map(|x| match x {
+            Int(i) => Ok(*i),
+            _ => Err(ErrString("expecting int args".to_string())),
+        })
+        .collect()
+}
+
+// Left fold over any number of ints. With a single argument the
+// identity is used as the left operand, so (- x) negates and (/ x)
+// inverts; with none the identity itself is returned.
+fn int_fold(a: MalArgs, identity: i64, op: fn(i64, i64) -> Option<i64>) -> MalRet {
+    let is = ints(&a)?;
+    let (init, rest) = match is.len() {
+        0 => return Ok(Int(identity)),
+        1 => (identity, &is[..]),
+        _ => (is[0], &is[1..]),
+    };
+    let mut acc = init;
+    for i in rest {
+        acc = match op(acc, *i) {
+            Some(r) => r,
+            None if *i == 0 => return error("divide by zero"),
+            None => return error("integer overflow"),
+        };
+    }
+    Ok(Int(acc))
+}
+
+// Chained comparison: true when every adjacent pair satisfies op.
+fn int_cmp(a: MalArgs, op: fn(&i64, &i64) -> bool) -> MalRet {
+    let is = ints(&a)?;
+    Ok(Bool(is.windows(2).all(|w| op(&w[0], &w[1]))))
+}
+
+fn all_equal(a: &MalArgs) -> bool {
+    a.windows(2).all(|w| w[0] == w[1])
+}
+
 fn symbol(a: MalArgs) -> MalRet {
     match a[0] {
-        Str(ref s) => Ok(Sym(s.to_string())),
+        Str(ref s) => crate::types::symbol(s),
         _ => error("illegal symbol call"),
     }
 }
 
-fn readline(a: MalArgs) -> MalRet {
-    lazy_static! {
-        static ref RL: Mutex<Editor<(), rustyline::history::DefaultHistory>>
-          = Mutex::new(Editor::<(), rustyline::history::DefaultHistory>::new().unwrap());
-    }
-    //let mut rl = Editor::<()>::new();
-
-    match a[0] {
-        Str(ref p) => {
-            //match rl.readline(p) {
-            match RL.lock().unwrap().readline(p) {
-                Ok(mut line) => {
-                    // Remove any trailing \n or \r\n
-                    if line.ends_with('\n') {
+// The line editor lives in the closure rather than in a global, and is
+// only created the first time readline is called.
+pub(crate) fn readline() -> impl Fn(MalArgs) -> MalRet {
+    let rl: RefCell<Option<Editor<(), DefaultHistory>>> = RefCell::new(None);
+    move |a| {
+        let p = match a[0] {
+            Str(ref p) => p,
+            _ => return error("readline: prompt is not Str"),
+        };
+        let mut rl = rl.borrow_mut();
+        if rl.is_none() {
+            match Editor::<(), DefaultHistory>::new() {
+                Ok(ed) => *rl = Some(ed),
+                Err(e) => return error(&format!("{:?}", e)),
+            }
+        }
+        match rl.as_mut().unwrap().readline(p) {
+            Ok(mut line) => {
+                // Remove any trailing \n or \r\n
+                if line.ends_with('\n') {
+                    line.pop();
+                    if line.ends_with('\r') {
                         line.pop();
-                        if line.ends_with('\r') {
-                            line.pop();
-                        }
                     }
-                    Ok(Str(line))
                 }
-                Err(ReadlineError::Eof) => Ok(Nil),
-                Err(e) => error(&format!("{:?}", e)),
+                Ok(Str(line))
             }
+            Err(ReadlineError::Eof) => Ok(Nil),
+            Err(e) => error(&format!("{:?}", e)),
         }
-        _ => error("readline: prompt is not Str"),
     }
 }
 
 fn slurp(f: &str) -> MalRet {
+    let mut file = File::open(f).map_err(|e| ErrString(e.to_string()))?;
+    if let Ok(meta) = file.metadata() {
+        check_size(meta.len() as usize)?;
+    }
     let mut s = String::new();
-    match File::open(f).and_then(|mut f| f.read_to_string(&mut s)) {
-        Ok(_) => Ok(Str(s)),
+    match file.read_to_string(&mut s) {
+        Ok(_) => sized(Ok(Str(s))),
         Err(e) => error(&e.to_string()),
     }
 }
 
+// Reads a form from s, which has no more elements than s has bytes
+fn read_string(s: &str) -> MalRet {
+    check_size(s.len())?;
+    read_str(s)
+}
+
 fn time_ms(_a: MalArgs) -> MalRet {
     let ms_e = match SystemTime::now().duration_since(UNIX_EPOCH) {
         Ok(d) => d,
@@ -98,11 +159,93 @@ fn time_ms(_a: MalArgs) -> MalRet {
     ))
 }
 
+// Options maps for the json builtins: each key must be one of the
+// known keywords, and a flag is on when its value is truthy.
+fn json_opts(name: &str, opts: Option<&MalVal>, known: &[&str]) -> Result<Vec<bool>, MalErr> {
+    let mut flags = vec![false; known.len()];
+    let hm = match opts {
+        None | Some(Nil) => return Ok(flags),
+        Some(Hash(hm, _)) => hm,
+        Some(_) => return Err(ErrString(format!("{}: options must be a map", name))),
+    };
+    for (k, v) in hm.iter() {
+        match known
+            .iter()
+            .position(|o| k.strip_prefix('\u{29e}') == Some(*o))
+        {
+            Some(i) => flags[i] = !matches!(v, Nil | Bool(false)),
+            None => {
+                return Err(ErrString(format!(
+                    "{}: unknown option {}",
+                    name,
+                    key_val(k).pr_str(true)
+                )))
+            }
+        }
+    }
+    Ok(flags)
+}
+
+fn keywordize(v: MalVal) -> MalRet {
+    match v {
+        Vector(v, _) => Ok(vector!(v
+            .iter()
+            .cloned()
+            .map(keywordize)
+            .collect::<Result<_, _>>()?)),
+        Hash(hm, _) => {
+            let kvs = hm
+                .iter()
+                .map(|(k, v)| Ok(vec![keyword(k), keywordize(v.clone())?]))
+                .collect::<Result<Vec<_>, MalErr>>()?;
+            hash_map(kvs.concat())
+        }
+        _ => Ok(v),
+    }
+}
+
+fn json_parse(a: MalArgs) -> MalRet {
+    let s = match &a[0] {
+        Str(s) => s,
+        _ => return error("json-parse: expecting a string"),
+    };
+    check_size(s.len())?;
+    let flags = json_opts("json-parse", a.get(1), &["keywordize"])?;
+    // serde_json reports the line and column of malformed input
+    let v: MalVal = serde_json::from_str(s).map_err(|e| ErrString(format!("json-parse: {}", e)))?;
+    if flags[0] {
+        keywordize(v)
+    } else {
+        Ok(v)
+    }
+}
+
+fn json_stringify(a: MalArgs) -> MalRet {
+    let flags = json_opts("json-stringify", a.get(1), &["pretty"])?;
+    let res = if flags[0] {
+        serde_json::to_string_pretty(&a[0])
+    } else {
+        serde_json::to_string(&a[0])
+    };
+    res.map(Str)
+        .map_err(|e| ErrString(format!("json-stringify: {}", e)))
+}
+
 fn get(a: MalArgs) -> MalRet {
     match (a[0].clone(), a[1].clone()) {
         (Nil, _) => Ok(Nil),
-        (Hash(ref hm, _), Str(ref s)) => match hm.get(s) {
-            Some(mv) => Ok(mv.clone()),
+        (Hash(ref hm, _), ref k) if hash_key(k).is_some() => {
+            match hm.get(&hash_key(k).unwrap()) {
+                Some(mv) => Ok(mv.clone()),
+                None => Ok(Nil),
+            }
+        }
+        (SortedMap(ref t, _), ref k) => match t.get(k)? {
+            Some((_, v)) => Ok(v),
+            None => Ok(Nil),
+        },
+        (SortedSet(ref t, _), ref k) => match t.get(k)? {
+            Some((k, _)) => Ok(k),
             None => Ok(Nil),
         },
         _ => error("illegal get args"),
@@ -112,6 +255,7 @@ fn get(a: MalArgs) -> MalRet {
 fn assoc(a: MalArgs) -> MalRet {
     match a[0] {
         Hash(ref hm, _) => _assoc((**hm).clone(), a[1..].to_vec()),
+        SortedMap(ref t, _) => _sorted_assoc((**t).clone(), a[1..].to_vec()),
         _ => error("assoc on non-Hash Map"),
     }
 }
@@ -119,27 +263,50 @@ fn assoc(a: MalArgs) -> MalRet {
 fn dissoc(a: MalArgs) -> MalRet {
     match a[0] {
         Hash(ref hm, _) => _dissoc((**hm).clone(), a[1..].to_vec()),
+        SortedMap(ref t, _) => _sorted_dissoc((**t).clone(), a[1..].to_vec()),
         _ => error("dissoc on non-Hash Map"),
     }
 }
 
+fn disj(a: MalArgs) -> MalRet {
+    match a[0] {
+        SortedSet(ref t, _) => _sorted_disj((**t).clone(), a[1..].to_vec()),
+        _ => error("disj on non-Set"),
+    }
+}
+
 fn contains_q(a: MalArgs) -> MalRet {
     match (a[0].clone(), a[1].clone()) {
-        (Hash(ref hm, _), Str(ref s)) => Ok(Bool(hm.contains_key(s))),
+        (Hash(ref hm, _), ref k) if hash_key(k).is_some() => {
+            Ok(Bool(hm.contains_key(&hash_key(k).unwrap())))
+        }
+        (SortedMap(ref t, _), ref k) | (SortedSet(ref t, _), ref k) => {
+            Ok(Bool(t.get(k)?.is_some()))
+        }
         _ => error("illegal get args"),
     }
 }
 
 fn keys(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => Ok(list!(hm.keys().map(|k| { Str(k.to_string()) }).collect())),
+        Hash(ref hm, _) => Ok(list!(hm
+            .keys()
+            .sorted()
+            .map(|k| key_val(k))
+            .collect())),
+        SortedMap(ref t, _) => Ok(list!(t.keys())),
         _ => error("keys requires Hash Map"),
     }
 }
 
 fn vals(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => Ok(list!(hm.values().cloned().collect())),
+        Hash(ref hm, _) => Ok(list!(hm
+            .iter()
+            .sorted_by(|(k1, _), (k2, _)| k1.cmp(k2))
+            .map(|(_, v)| v.clone())
+            .collect())),
+        SortedMap(ref t, _) => Ok(list!(t.entries().into_iter().map(|(_, v)| v).collect())),
         _ => error("keys requires Hash Map"),
     }
 }
@@ -208,24 +375,24 @@ fn rest(a: MalArgs) -> MalRet {
     }
 }
 
-fn apply(a: MalArgs) -> MalRet {
+fn apply(a: MalArgs, ctx: &Context) -> MalRet {
     match a[a.len() - 1] {
         List(ref v, _) | Vector(ref v, _) => {
             let f = &a[0];
             let mut fargs = a[1..a.len() - 1].to_vec();
             fargs.extend_from_slice(v);
-            f.apply(fargs)
+            f.apply_ctx(fargs, Some(ctx))
         }
         _ => error("apply called with non-seq"),
     }
 }
 
-fn map(a: MalArgs) -> MalRet {
+fn map(a: MalArgs, ctx: &Context) -> MalRet {
     match a[1] {
         List(ref v, _) | Vector(ref v, _) => {
             let mut res = vec![];
             for mv in v.iter() {
-                res.push(a[0].apply(vec![mv.clone()])?)
+                res.push(a[0].apply_ctx(vec![mv.clone()], Some(ctx))?)
             }
             Ok(list!(res))
         }
@@ -244,6 +411,7 @@ fn conj(a: MalArgs) -> MalRet {
             Ok(list!([&sl[..], v].concat()))
         }
         Vector(ref v, _) => Ok(vector!([v, &a[1..]].concat())),
+        SortedSet(ref t, _) => _sorted_conj((**t).clone(), a[1..].to_vec()),
         _ => error("conj: called with non-seq"),
     }
 }
@@ -253,100 +421,360 @@ fn seq(a: MalArgs) -> MalRet {
         List(ref v, _) | Vector(ref v, _) if v.len() == 0 => Ok(Nil),
         List(ref v, _) | Vector(ref v, _) => Ok(list!(v.to_vec())),
         Str(ref s) if s.is_empty() => Ok(Nil),
-        Str(ref s) if !a[0].keyword_q() => {
+        Str(ref s) => {
             Ok(list!(s.chars().map(|c| { Str(c.to_string()) }).collect()))
         }
+        SortedMap(ref t, _) | SortedSet(ref t, _) if t.is_empty() => Ok(Nil),
+        SortedMap(ref t, _) => Ok(list!(t
+            .entries()
+            .into_iter()
+            .map(|(k, v)| vector![k, v])
+            .collect())),
+        SortedSet(ref t, _) => Ok(list!(t.keys())),
         Nil => Ok(Nil),
         _ => error("seq: called with non-seq"),
     }
 }
 
+// subseq/rsubseq take (coll test key) or (coll start-test start-key
+// end-test end-key). Each test is applied to (compare k key) and 0, so
+// probing it with -1, 0 and 1 tells which kind of bound it is.
+fn range_bound(test: &MalVal) -> Result<(bool, bool), MalErr> {
+    let mut r = vec![];
+    for i in [1, 0, -1] {
+        r.push(!matches!(
+            test.apply(vec![Int(i), Int(0)])?,
+            Bool(false) | Nil
+        ));
+    }
+    match r[..] {
+        [true, false, false] => Ok((true, false)),
+        [true, true, false] => Ok((true, true)),
+        [false, false, true] => Ok((false, false)),
+        [false, true, true] => Ok((false, true)),
+        _ => Err(ErrString(
+            "subseq: test must be one of <, <=, > or >=".to_string(),
+        )),
+    }
+}
+
+fn range(name: &str, a: &MalArgs) -> Result<Vec<(MalVal, MalVal)>, MalErr> {
+    let t = match a[0] {
+        SortedMap(ref t, _) | SortedSet(ref t, _) => t,
+        _ => {
+            return Err(ErrString(
+                "subseq: called with non-sorted collection".to_string(),
+            ))
+        }
+    };
+    if a.len() == 4 {
+        return Err(ErrString(format!(
+            "wrong number of args (4) passed to {}",
+            name
+        )));
+    }
+    let (mut lower, mut upper) = (None, None);
+    for (test, key) in a[1..].iter().tuples() {
+        match range_bound(test)? {
+            (true, inclusive) => lower = Some((key, inclusive)),
+            (false, inclusive) => upper = Some((key, inclusive)),
+        }
+    }
+    t.range(lower, upper)
+}
+
+fn subseq_entries(a: &MalArgs, entries: Vec<(MalVal, MalVal)>) -> MalRet {
+    if entries.is_empty() {
+        return Ok(Nil);
+    }
+    Ok(list!(match a[0] {
+        SortedMap(_, _) => entries.into_iter().map(|(k, v)| vector![k, v]).collect(),
+        _ => entries.into_iter().map(|(k, _)| k).collect(),
+    }))
+}
+
+fn subseq(a: MalArgs) -> MalRet {
+    let entries = range("subseq", &a)?;
+    subseq_entries(&a, entries)
+}
+
+fn rsubseq(a: MalArgs) -> MalRet {
+    let mut entries = range("rsubseq", &a)?;
+    entries.reverse();
+    subseq_entries(&a, entries)
+}
+
+fn compare(a: MalArgs) -> MalRet {
+    Ok(Int(a[0].compare(&a[1])? as i64))
+}
+
+// Stable sort of the items of a sequence. The first error raised by
+// the comparator or a key function aborts the sort and is returned.
+fn sort_seq(seq: &MalVal, keyfn: Option<&MalVal>, comparator: Option<&MalVal>) -> MalRet {
+    let items = match seq {
+        List(v, _) | Vector(v, _) => v.to_vec(),
+        SortedMap(_, _) | SortedSet(_, _) => match self::seq(vec![seq.clone()])? {
+            List(v, _) => v.to_vec(),
+            _ => vec![],
+        },
+        Nil => vec![],
+        _ => return error("sort: called with non-seq"),
+    };
+    let mut keyed = vec![];
+    for item in items {
+        let key = match keyfn {
+            Some(f) => f.apply(vec![item.clone()])?,
+            None => item.clone(),
+        };
+        keyed.push((key, item));
+    }
+    let sorted = merge_sort(keyed, &mut |(a, _), (b, _)| compare_with(comparator, a, b))?;
+    Ok(list!(sorted.into_iter().map(|(_, item)| item).collect()))
+}
+
+// Stable merge sort. Unlike slice::sort_by it stops at the first error
+// and gives some order, without panicking, for a comparator that is
+// not consistent.
+fn merge_sort<T>(
+    mut v: Vec<T>,
+    cmp: &mut impl FnMut(&T, &T) -> Result<Ordering, MalErr>,
+) -> Result<Vec<T>, MalErr> {
+    if v.len() < 2 {
+        return Ok(v);
+    }
+    let right = v.split_off(v.len() / 2);
+    let left = merge_sort(v, cmp)?;
+    let right = merge_sort(right, cmp)?;
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(l), Some(r)) = (left.peek(), right.peek()) {
+        let next = match cmp(r, l)? {
+            Ordering::Less => right.next(),
+            _ => left.next(),
+        };
+        merged.extend(next);
+    }
+    merged.extend(left);
+    merged.extend(right);
+    Ok(merged)
+}
+
+fn sort(a: MalArgs) -> MalRet {
+    match a.len() {
+        1 => sort_seq(&a[0], None, None),
+        _ => sort_seq(&a[1], None, Some(&a[0])),
+    }
+}
+
+fn sort_by(a: MalArgs) -> MalRet {
+    match a.len() {
+        2 => sort_seq(&a[1], Some(&a[0]), None),
+        _ => sort_seq(&a[2], Some(&a[0]), Some(&a[1])),
+    }
+}
+
+// eval always works in the outermost (REPL) environment
+fn eval(a: MalArgs, ctx: &Context) -> MalRet {
+    (ctx.eval)(&a[0], &env_find_repl(ctx.env))
+}
+
+fn resolve(a: MalArgs, ctx: &Context) -> MalRet {
+    match a[0] {
+        Sym(s) => Ok(env_get(ctx.env, s).unwrap_or(Nil)),
+        _ => error("resolve: expecting a symbol"),
+    }
+}
+
+fn bound_q(a: MalArgs, ctx: &Context) -> MalRet {
+    match a[0] {
+        Sym(s) => Ok(Bool(env_get(ctx.env, s).is_some())),
+        _ => error("bound?: expecting a symbol"),
+    }
+}
+
+fn ns_publics(_a: MalArgs, ctx: &Context) -> MalRet {
+    let hm = env_entries(&env_find_repl(ctx.env))
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect::<FnvHashMap<String, MalVal>>();
+    Ok(new_hash(hm))
+}
+
+// (edn-read-string s) or (edn-read-string opts s). opts may hold
+// :readers, a map from tag names to functions of the tagged value, and
+// :default, a function of the tag symbol and value for other tags.
+fn edn_read_string(a: MalArgs, ctx: &Context) -> MalRet {
+    let (opts, s) = match &a[..] {
+        [Str(s)] => (Nil, s),
+        [opts, Str(s)] => (opts.clone(), s),
+        _ => return error("edn-read-string: expecting a string"),
+    };
+    check_size(s.len())?;
+    let (mut readers, mut default) = (FnvHashMap::default(), None);
+    match opts {
+        Nil => {}
+        Hash(ref hm, _) => {
+            for (k, v) in hm.iter() {
+                match (k.strip_prefix('\u{29e}'), v) {
+                    (Some("readers"), Hash(r, _)) => readers = (**r).clone(),
+                    (Some("default"), f) => default = Some(f.clone()),
+                    _ => {
+                        return error(&format!(
+                            "edn-read-string: invalid option {}",
+                            key_val(k).pr_str(true)
+                        ))
+                    }
+                }
+            }
+        }
+        _ => return error("edn-read-string: options must be a map"),
+    }
+    read_edn(s, &mut |tag, v| match (readers.get(tag), &default) {
+        (Some(f), _) => f.apply_ctx(vec![v], Some(ctx)),
+        (None, Some(f)) => f.apply_ctx(vec![crate::types::symbol(tag)?, v], Some(ctx)),
+        (None, None) => error(&format!("edn: no reader function for tag {}", tag)),
+    })
+}
+
+type CoreFn = fn(MalArgs) -> MalRet;
+type CoreCtxFn = fn(MalArgs, &Context) -> MalRet;
+
 pub fn ns() -> Vec<(&'static str, MalVal)> {
-    vec![
-        ("=", func(|a| Ok(Bool(a[0] == a[1])))),
-        ("throw", func(|a| Err(ErrMalVal(a[0].clone())))),
-        ("nil?", func(fn_is_type!(Nil))),
-        ("true?", func(fn_is_type!(Bool(true)))),
-        ("false?", func(fn_is_type!(Bool(false)))),
-        ("symbol", func(symbol)),
-        ("symbol?", func(fn_is_type!(Sym(_)))),
+    let builtins: Vec<(&'static str, Arity, CoreFn)> = vec![
+        ("=", AtLeast(1), |a| Ok(Bool(all_equal(&a)))),
+        ("not=", AtLeast(1), |a| Ok(Bool(!all_equal(&a)))),
+        ("throw", Exactly(1), |a| Err(ErrMalVal(a[0].clone()))),
+        ("nil?", Exactly(1), fn_is_type!(Nil)),
+        ("true?", Exactly(1), fn_is_type!(Bool(true))),
+        ("false?", Exactly(1), fn_is_type!(Bool(false))),
+        ("symbol", Exactly(1), symbol),
+        ("symbol?", Exactly(1), fn_is_type!(Sym(_))),
         (
             "string?",
-            func(fn_is_type!(Str(ref s) if !s.starts_with('\u{29e}'))),
+            Exactly(1),
+            fn_is_type!(Str(_)),
         ),
-        ("keyword", func(|a| a[0].keyword())),
+        ("keyword", Exactly(1), |a| a[0].keyword()),
         (
             "keyword?",
-            func(fn_is_type!(Str(ref s) if s.starts_with('\u{29e}'))),
+            Exactly(1),
+            fn_is_type!(Kw(_)),
         ),
-        ("number?", func(fn_is_type!(Int(_)))),
+        ("number?", Exactly(1), fn_is_type!(Int(_))),
         (
             "fn?",
-            func(fn_is_type!(MalFunc{is_macro,..} if !is_macro,Func(_,_))),
+            Exactly(1),
+            fn_is_type!(MalFunc{is_macro,..} if !is_macro,Func(_,_)),
         ),
         (
             "macro?",
-            func(fn_is_type!(MalFunc{is_macro,..} if is_macro)),
+            Exactly(1),
+            fn_is_type!(MalFunc{is_macro,..} if is_macro),
         ),
-        ("pr-str", func(|a| Ok(Str(pr_seq(&a, true, "", "", " "))))),
-        ("str", func(|a| Ok(Str(pr_seq(&a, false, "", "", ""))))),
+        ("pr-str", AtLeast(0), |a| {
+            sized(Ok(Str(pr_seq(&a, true, "", "", " "))))
+        }),
+        ("str", AtLeast(0), |a| {
+            sized(Ok(Str(pr_seq(&a, false, "", "", ""))))
+        }),
+        ("prn", AtLeast(0), |a| {
+            println!("{}", pr_seq(&a, true, "", "", " "));
+            Ok(Nil)
+        }),
+        ("println", AtLeast(0), |a| {
+            println!("{}", pr_seq(&a, false, "", "", " "));
+            Ok(Nil)
+        }),
+        ("read-string", Exactly(1), fn_str!(read_string)),
+        ("slurp", Exactly(1), fn_str!(slurp)),
+        ("json-parse", Between(1, 2), json_parse),
+        ("json-stringify", Between(1, 2), |a| {
+            sized(json_stringify(a))
+        }),
+        ("edn-pr-str", Exactly(1), |a| sized(a[0].pr_edn().map(Str))),
+        ("<", AtLeast(1), |a| int_cmp(a, i64::lt)),
+        ("<=", AtLeast(1), |a| int_cmp(a, i64::le)),
+        (">", AtLeast(1), |a| int_cmp(a, i64::gt)),
+        (">=", AtLeast(1), |a| int_cmp(a, i64::ge)),
+        ("+", AtLeast(0), |a| int_fold(a, 0, i64::checked_add)),
+        ("-", AtLeast(1), |a| int_fold(a, 0, i64::checked_sub)),
+        ("*", AtLeast(0), |a| int_fold(a, 1, i64::checked_mul)),
+        ("/", AtLeast(1), |a| int_fold(a, 1, i64::checked_div)),
+        ("compare", Exactly(2), compare),
+        ("sort", Between(1, 2), sort),
+        ("sort-by", Between(2, 3), sort_by),
+        ("time-ms", Exactly(0), time_ms),
         (
-            "prn",
-            func(|a| {
-                println!("{}", pr_seq(&a, true, "", "", " "));
-                Ok(Nil)
-            }),
+            "sequential?",
+            Exactly(1),
+            fn_is_type!(List(_, _), Vector(_, _)),
         ),
+        ("list", AtLeast(0), |a| sized(Ok(list!(a.to_vec())))),
+        ("list?", Exactly(1), fn_is_type!(List(_, _))),
+        ("vector", AtLeast(0), |a| sized(Ok(vector!(a.to_vec())))),
+        ("vector?", Exactly(1), fn_is_type!(Vector(_, _))),
+        ("hash-map", AtLeast(0), |a| sized(hash_map(a))),
+        ("map?", Exactly(1), fn_is_type!(Hash(_, _), SortedMap(_, _))),
+        ("sorted-map", AtLeast(0), |a| sized(sorted_map(None, a))),
+        ("sorted-map-by", AtLeast(1), |a| {
+            sized(sorted_map(Some(a[0].clone()), a[1..].to_vec()))
+        }),
+        ("sorted-set", AtLeast(0), |a| sized(sorted_set(None, a))),
+        ("sorted-set-by", AtLeast(1), |a| {
+            sized(sorted_set(Some(a[0].clone()), a[1..].to_vec()))
+        }),
+        ("set?", Exactly(1), fn_is_type!(SortedSet(_, _))),
         (
-            "println",
-            func(|a| {
-                println!("{}", pr_seq(&a, false, "", "", " "));
-                Ok(Nil)
-            }),
+            "sorted?",
+            Exactly(1),
+            fn_is_type!(SortedMap(_, _), SortedSet(_, _)),
         ),
-        ("read-string", func(fn_str!(read_str))),
-        ("readline", func(readline)),
-        ("slurp", func(fn_str!(slurp))),
-        ("<", func(fn_t_int_int!(Bool, |i, j| { i < j }))),
-        ("<=", func(fn_t_int_int!(Bool, |i, j| { i <= j }))),
-        (">", func(fn_t_int_int!(Bool, |i, j| { i > j }))),
-        (">=", func(fn_t_int_int!(Bool, |i, j| { i >= j }))),
-        ("+", func(fn_t_int_int!(Int, |i, j| { i + j }))),
-        ("-", func(fn_t_int_int!(Int, |i, j| { i - j }))),
-        ("*", func(fn_t_int_int!(Int, |i, j| { i * j }))),
-        ("/", func(fn_t_int_int!(Int, |i, j| { i / j }))),
-        ("time-ms", func(time_ms)),
-        ("sequential?", func(fn_is_type!(List(_, _), Vector(_, _)))),
-        ("list", func(|a| Ok(list!(a.to_vec())))),
-        ("list?", func(fn_is_type!(List(_, _)))),
-        ("vector", func(|a| Ok(vector!(a.to_vec())))),
-        ("vector?", func(fn_is_type!(Vector(_, _)))),
-        ("hash-map", func(hash_map)),
-        ("map?", func(fn_is_type!(Hash(_, _)))),
-        ("assoc", func(assoc)),
-        ("dissoc", func(dissoc)),
-        ("get", func(get)),
-        ("contains?", func(contains_q)),
-        ("keys", func(keys)),
-        ("vals", func(vals)),
-        ("vec", func(vec)),
-        ("cons", func(cons)),
-        ("concat", func(concat)),
-        ("empty?", func(|a| a[0].empty_q())),
-        ("nth", func(nth)),
-        ("first", func(first)),
-        ("rest", func(rest)),
-        ("count", func(|a| a[0].count())),
-        ("apply", func(apply)),
-        ("map", func(map)),
-        ("conj", func(conj)),
-        ("seq", func(seq)),
-        ("meta", func(|a| a[0].get_meta())),
-        ("with-meta", func(|a| a[0].clone().with_meta(&a[1]))),
-        ("atom", func(|a| Ok(atom(&a[0])))),
-        ("atom?", func(fn_is_type!(Atom(_)))),
-        ("deref", func(|a| a[0].deref())),
-        ("reset!", func(|a| a[0].reset_bang(&a[1]))),
-        ("swap!", func(|a| a[0].swap_bang(&a[1..].to_vec()))),
-    ]
+        ("disj", AtLeast(1), disj),
+        ("subseq", Between(3, 5), subseq),
+        ("rsubseq", Between(3, 5), rsubseq),
+        ("assoc", AtLeast(1), |a| sized(assoc(a))),
+        ("dissoc", AtLeast(1), dissoc),
+        ("get", Exactly(2), get),
+        ("contains?", Exactly(2), contains_q),
+        ("keys", Exactly(1), |a| sized(keys(a))),
+        ("vals", Exactly(1), |a| sized(vals(a))),
+        ("vec", Exactly(1), |a| sized(vec(a))),
+        ("cons", Exactly(2), |a| sized(cons(a))),
+        ("concat", AtLeast(0), |a| sized(concat(a))),
+        ("empty?", Exactly(1), |a| a[0].empty_q()),
+        ("nth", Exactly(2), nth),
+        ("first", Exactly(1), first),
+        ("rest", Exactly(1), rest),
+        ("count", Exactly(1), |a| a[0].count()),
+        ("conj", AtLeast(1), |a| sized(conj(a))),
+        ("seq", Exactly(1), |a| sized(seq(a))),
+        ("meta", Exactly(1), |a| a[0].get_meta()),
+        ("with-meta", Exactly(2), |a| a[0].clone().with_meta(&a[1])),
+        ("atom", Exactly(1), |a| Ok(atom(&a[0]))),
+        ("atom?", Exactly(1), fn_is_type!(Atom(_))),
+        ("deref", Exactly(1), |a| a[0].deref()),
+        ("reset!", Exactly(2), |a| a[0].reset_bang(&a[1])),
+        ("swap!", AtLeast(2), |a| a[0].swap_bang(&a[1..].to_vec())),
+    ];
+    let mut ns: Vec<(&'static str, MalVal)> = builtins
+        .into_iter()
+        .map(|(name, arity, f)| (name, builtin(name, arity, f)))
+        .collect();
+    let ctx_builtins: Vec<(&'static str, Arity, CoreCtxFn)> = vec![
+        ("eval", Exactly(1), eval),
+        ("resolve", Exactly(1), resolve),
+        ("bound?", Exactly(1), bound_q),
+        ("ns-publics", Exactly(0), ns_publics),
+        ("apply", AtLeast(2), apply),
+        ("map", Exactly(2), |a, ctx| sized(map(a, ctx))),
+        ("edn-read-string", Between(1, 2), edn_read_string),
+    ];
+    ns.extend(
+        ctx_builtins
+            .into_iter()
+            .map(|(name, arity, f)| (name, builtin_ctx(name, arity, f))),
+    );
+    ns.push(("readline", builtin("readline", Exactly(1), readline())));
+    ns
 }
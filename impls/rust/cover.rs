@@ -0,0 +1,193 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::rc::{Rc, Weak};
+
+use fnv::FnvHashMap;
+
+use crate::reader::read_str_lines;
+use crate::types::MalVal::List;
+use crate::types::{MalRet, MalVal};
+
+// Line and branch coverage of the files run with load-file. While it
+// is on, each list read from such a file is a site, and the compiler
+// marks the sites it compiles as code so that the evaluators count how
+// often they run. Each branch of an if compiled within such a site,
+// including one a macro like cond expands to, gets a site of its own
+// on the same line. Lines with a compiled site are reported in LCOV
+// format, with the count of the site on them that ran least, so that
+// e.g. a cond clause whose test ran but whose branch did not is not
+// covered, and the branches with the count of each.
+//
+// Compiled code refers to its sites by index, so they are kept for as
+// long as the thread runs.
+
+thread_local! {
+    static COVERING: Cell<bool> = const { Cell::new(false) };
+    static COVERAGE: RefCell<Coverage> = RefCell::new(Coverage::default());
+    // the site of the list being compiled
+    static COMPILING: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+struct Site {
+    list: Weak<Vec<MalVal>>,
+    file: usize,
+    line: usize,
+    compiled: bool,
+    hits: u64,
+    // for the branches of an if: the if's number and which branch
+    branch: Option<(usize, usize)>,
+}
+
+#[derive(Default)]
+struct Coverage {
+    files: Vec<String>,
+    sites: Vec<Site>,
+    // sites by the address of their list
+    by_list: FnvHashMap<usize, usize>,
+}
+
+// Starts recording coverage afresh, or stops and keeps what was
+// recorded for lcov.
+pub fn set_coverage(on: bool) {
+    if on {
+        COVERAGE.with(|c| {
+            for site in c.borrow_mut().sites.iter_mut() {
+                site.hits = 0;
+            }
+        });
+    }
+    COVERING.with(|c| c.set(on));
+}
+
+pub fn enabled() -> bool {
+    COVERING.with(|c| c.get())
+}
+
+// Reads src, the contents of the file at path, noting where its lists
+// came from.
+pub fn read_file(path: &str, src: &str) -> MalRet {
+    let (form, lists) = read_str_lines(src)?;
+    COVERAGE.with(|c| {
+        let c = &mut *c.borrow_mut();
+        let file = match c.files.iter().position(|f| f == path) {
+            Some(i) => i,
+            None => {
+                c.files.push(path.to_string());
+                c.files.len() - 1
+            }
+        };
+        for (list, line) in lists {
+            if let List(l, _) = list {
+                c.by_list.insert(Rc::as_ptr(&l) as usize, c.sites.len());
+                c.sites.push(Site {
+                    list: Rc::downgrade(&l),
+                    file,
+                    line,
+                    compiled: false,
+                    hits: 0,
+                    branch: None,
+                });
+            }
+        }
+    });
+    Ok(form)
+}
+
+// The site of a form about to be compiled, if it was read from a file
+pub fn site(form: &MalVal) -> Option<usize> {
+    let l = match form {
+        List(l, _) => l,
+        _ => return None,
+    };
+    COVERAGE.with(|c| {
+        let c = c.borrow();
+        let i = *c.by_list.get(&(Rc::as_ptr(l) as usize))?;
+        // the address may have been reused since
+        match c.sites[i].list.upgrade() {
+            Some(s) if Rc::ptr_eq(&s, l) => Some(i),
+            _ => None,
+        }
+    })
+}
+
+// Marks a site as compiled to code that counts its runs
+pub fn compiled(site: usize) {
+    COVERAGE.with(|c| c.borrow_mut().sites[site].compiled = true)
+}
+
+// Runs f, the compiling of the list at site, so that the branches of
+// the ifs in it are put on its line.
+pub fn within<T>(site: Option<usize>, f: impl FnOnce() -> T) -> T {
+    if site.is_none() {
+        return f();
+    }
+    let outer = COMPILING.with(|c| c.replace(site));
+    let res = f();
+    COMPILING.with(|c| c.set(outer));
+    res
+}
+
+// New sites for the then and else branches of an if being compiled,
+// if it is within a site
+pub fn branches() -> Option<(usize, usize)> {
+    let within = COMPILING.with(|c| c.get())?;
+    COVERAGE.with(|c| {
+        let c = &mut *c.borrow_mut();
+        let (file, line) = (c.sites[within].file, c.sites[within].line);
+        let first = c.sites.len();
+        for branch in 0..2 {
+            c.sites.push(Site {
+                list: Weak::new(),
+                file,
+                line,
+                compiled: true,
+                hits: 0,
+                branch: Some((first, branch)),
+            });
+        }
+        Some((first, first + 1))
+    })
+}
+
+pub fn hit(site: usize) {
+    COVERAGE.with(|c| {
+        if let Some(site) = c.borrow_mut().sites.get_mut(site) {
+            site.hits += 1
+        }
+    })
+}
+
+// An LCOV tracefile with a record for each file
+pub fn lcov() -> String {
+    COVERAGE.with(|c| {
+        let c = c.borrow();
+        let mut out = String::new();
+        for (i, path) in c.files.iter().enumerate() {
+            let mut lines: BTreeMap<usize, u64> = BTreeMap::new();
+            let mut branches = vec![];
+            for s in c.sites.iter().filter(|s| s.file == i && s.compiled) {
+                match s.branch {
+                    Some((block, branch)) => branches.push((s.line, block, branch, s.hits)),
+                    None => {
+                        let hits = lines.entry(s.line).or_insert(s.hits);
+                        *hits = (*hits).min(s.hits);
+                    }
+                }
+            }
+            let _ = writeln!(out, "TN:\nSF:{}", path);
+            branches.sort();
+            for (line, block, branch, hits) in branches.iter() {
+                let _ = writeln!(out, "BRDA:{},{},{},{}", line, block, branch, hits);
+            }
+            let taken = branches.iter().filter(|b| b.3 > 0).count();
+            let _ = writeln!(out, "BRF:{}\nBRH:{}", branches.len(), taken);
+            for (line, hits) in lines.iter() {
+                let _ = writeln!(out, "DA:{},{}", line, hits);
+            }
+            let hit = lines.values().filter(|h| **h > 0).count();
+            let _ = writeln!(out, "LF:{}\nLH:{}\nend_of_record", lines.len(), hit);
+        }
+        out
+    })
+}
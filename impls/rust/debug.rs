@@ -0,0 +1,147 @@
+use std::cell::{Cell, RefCell};
+
+use rustyline::history::DefaultHistory;
+use rustyline::Editor;
+
+use crate::env::{env_entries, Env};
+use crate::reader::read_str;
+use crate::trace;
+use crate::types::MalErr::ErrString;
+use crate::types::MalVal::Nil;
+use crate::types::{Context, EvalFn, MalArgs, MalErr, MalRet, MalVal};
+
+// A debugger for mal code. In debug mode `(break)` stops in a nested
+// REPL in the env it was called from, where expressions can be
+// evaluated and the program stepped call by call through the hooks in
+// trace.rs. Outside debug mode `(break)` does nothing.
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Step {
+    Off,
+    // stop at the next call
+    Into,
+    // stop at the next call at this depth or less
+    Over(usize),
+}
+
+thread_local! {
+    static DEBUG: Cell<bool> = const { Cell::new(false) };
+    static STEP: Cell<Step> = const { Cell::new(Step::Off) };
+    static EVAL: Cell<Option<EvalFn>> = const { Cell::new(None) };
+    static EDITOR: RefCell<Option<Editor<(), DefaultHistory>>> = const { RefCell::new(None) };
+}
+
+const HELP: &str = "\
+:locals      show the variables of the enclosing fn*, let* and catch* forms
+:step, :s    stop again at the next call
+:next, :n    stop again at the next call that is not inside this one
+:continue, :c run until the next (break)
+:abort, :a   stop evaluating with an error
+Anything else is evaluated in the current env.";
+
+// Sets debug mode and the evaluator for the nested REPL, stepping no
+// more if debug mode changes, and returns what they were
+pub fn swap_debug(on: bool, eval: Option<EvalFn>) -> (bool, Option<EvalFn>) {
+    let was = (
+        DEBUG.with(|d| d.replace(on)),
+        EVAL.with(|e| e.replace(eval)),
+    );
+    if was.0 != on {
+        set_step(Step::Off);
+    }
+    was
+}
+
+fn set_step(step: Step) {
+    STEP.with(|s| s.set(step));
+    trace::update_active();
+}
+
+pub fn stepping() -> bool {
+    STEP.with(|s| s.get()) != Step::Off
+}
+
+// Called by trace::enter for each call while stepping
+pub fn step(form: &MalVal, env: &Env, depth: usize) -> Result<(), MalErr> {
+    let stop = match STEP.with(|s| s.get()) {
+        Step::Off => false,
+        Step::Into => true,
+        Step::Over(d) => depth <= d,
+    };
+    match EVAL.with(|e| e.get()) {
+        Some(eval) if stop => pause("Step", Some(form), env, eval, depth),
+        _ => Ok(()),
+    }
+}
+
+pub fn break_fn(_a: MalArgs, ctx: &Context) -> MalRet {
+    if DEBUG.with(|d| d.get()) {
+        pause("Break", None, ctx.env, ctx.eval, trace::depth())?;
+    }
+    Ok(Nil)
+}
+
+fn read_line(prompt: &str) -> Option<String> {
+    EDITOR.with(|ed| {
+        let mut ed = ed.borrow_mut();
+        if ed.is_none() {
+            *ed = Editor::<(), DefaultHistory>::new().ok();
+        }
+        let ed = ed.as_mut()?;
+        let line = ed.readline(prompt).ok()?;
+        let _ = ed.add_history_entry(&line);
+        Some(line)
+    })
+}
+
+fn show_locals(env: &Env) {
+    let mut env = env;
+    while let Some(outer) = &env.outer {
+        let mut entries = env_entries(env);
+        entries.sort_by_key(|(k, _)| *k);
+        for (k, v) in entries {
+            println!("  {} = {}", k, v.pr_str(true));
+        }
+        env = outer;
+    }
+}
+
+fn pause(
+    what: &str,
+    form: Option<&MalVal>,
+    env: &Env,
+    eval: EvalFn,
+    depth: usize,
+) -> Result<(), MalErr> {
+    match form {
+        Some(form) => println!("{}: {} (:help for commands)", what, form.pr_str(true)),
+        None => println!("{} (:help for commands)", what),
+    }
+    // nothing evaluated here is stepped through
+    set_step(Step::Off);
+    loop {
+        let line = match read_line("debug> ") {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+        match line.trim() {
+            "" => (),
+            ":help" => println!("{}", HELP),
+            ":locals" => show_locals(env),
+            ":step" | ":s" => {
+                set_step(Step::Into);
+                return Ok(());
+            }
+            ":next" | ":n" => {
+                set_step(Step::Over(depth));
+                return Ok(());
+            }
+            ":continue" | ":c" => return Ok(()),
+            ":abort" | ":a" => return Err(ErrString("debugger: aborted".to_string())),
+            src => match read_str(src).and_then(|ast| eval(&ast, env)) {
+                Ok(v) => println!("{}", v.pr_str(true)),
+                Err(e) => println!("Error: {}", e),
+            },
+        }
+    }
+}
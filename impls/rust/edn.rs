@@ -0,0 +1,167 @@
+use std::rc::Rc;
+
+use itertools::Itertools;
+use regex::Regex;
+
+use crate::reader::{read_atom, tokenize, Reader};
+use crate::types::MalErr::ErrString;
+use crate::types::MalVal::{
+    Atom, Func, Hash, Int, List, MalFunc, Nil, SortedMap, SortedSet, Sym, Vector,
+};
+use crate::types::{error, hash_map, key_val, sorted_set, MalErr, MalRet, MalVal};
+
+// EDN reading and printing, for exchanging data only. There are no
+// reader macros, so nothing read this way is ever evaluated; tagged
+// literals are handed to a callback together with the form after the
+// tag. Nesting is bounded so that untrusted input cannot overflow the
+// stack.
+
+const MAX_DEPTH: usize = 512;
+
+pub type TagFn<'a> = dyn FnMut(&str, MalVal) -> MalRet + 'a;
+
+fn read_edn_atom(rdr: &mut Reader) -> MalRet {
+    lazy_static! {
+        static ref NUM_RE: Regex = Regex::new(r"^[-+]?[0-9]").unwrap();
+    }
+    let token = rdr.peek()?;
+    match read_atom(rdr)? {
+        Sym(_) if NUM_RE.is_match(&token) => error(&format!("edn: unsupported number {}", token)),
+        Sym(_) if token.starts_with('\\') => error("edn: characters are not supported"),
+        v => Ok(v),
+    }
+}
+
+fn read_edn_seq(
+    rdr: &mut Reader,
+    end: &str,
+    tag: &mut TagFn,
+    depth: usize,
+) -> Result<Vec<MalVal>, MalErr> {
+    let mut seq: Vec<MalVal> = vec![];
+    rdr.next()?;
+    loop {
+        let token = match rdr.peek() {
+            Ok(t) => t,
+            Err(_) => return Err(ErrString(format!("expected '{}', got EOF", end))),
+        };
+        if token == end {
+            break;
+        }
+        if token == "#_" {
+            rdr.next()?;
+            read_edn_form(rdr, tag, depth + 1)?;
+            continue;
+        }
+        seq.push(read_edn_form(rdr, tag, depth + 1)?)
+    }
+    let _ = rdr.next();
+    Ok(seq)
+}
+
+fn read_edn_form(rdr: &mut Reader, tag: &mut TagFn, depth: usize) -> MalRet {
+    if depth > MAX_DEPTH {
+        return error("edn: nesting too deep");
+    }
+    let token = match rdr.peek() {
+        Ok(t) => t,
+        Err(_) => return error("edn: unexpected EOF"),
+    };
+    match &token[..] {
+        "(" => Ok(list!(read_edn_seq(rdr, ")", tag, depth)?)),
+        "[" => Ok(vector!(read_edn_seq(rdr, "]", tag, depth)?)),
+        "{" => {
+            let kvs = read_edn_seq(rdr, "}", tag, depth)?;
+            let n = kvs.len();
+            match hash_map(kvs)? {
+                Hash(ref hm, _) if hm.len() * 2 != n => error("edn: duplicate map key"),
+                map => Ok(map),
+            }
+        }
+        // the tokenizer splits "#{" into "#" and "{"
+        "#" => {
+            rdr.next()?;
+            if rdr.peek().ok().as_deref() != Some("{") {
+                return error("edn: expected '{' after '#'");
+            }
+            let items = read_edn_seq(rdr, "}", tag, depth)?;
+            let n = items.len();
+            let set = sorted_set(None, items)?;
+            match set.count()? {
+                Int(c) if c as usize != n => error("edn: duplicate set element"),
+                _ => Ok(set),
+            }
+        }
+        "#_" => {
+            rdr.next()?;
+            read_edn_form(rdr, tag, depth + 1)?;
+            read_edn_form(rdr, tag, depth)
+        }
+        ")" | "]" | "}" => error(&format!("edn: unexpected '{}'", token)),
+        "'" | "`" | "~" | "~@" | "^" | "@" => {
+            error(&format!("edn: unsupported reader macro '{}'", token))
+        }
+        _ if token.starts_with('#') => {
+            rdr.next()?;
+            let v = read_edn_form(rdr, tag, depth + 1)?;
+            tag(&token[1..], v)
+        }
+        _ => read_edn_atom(rdr),
+    }
+}
+
+// Reads the first EDN form in `str`, or nil if there is none.
+pub fn read_edn(str: &str, tag: &mut TagFn) -> MalRet {
+    let tokens = tokenize(str);
+    if tokens.is_empty() {
+        return Ok(Nil);
+    }
+    read_edn_form(
+        &mut Reader {
+            pos: 0,
+            tokens,
+            data: true,
+            ..Default::default()
+        },
+        tag,
+        0,
+    )
+}
+
+fn pr_edn_seq(seq: &[MalVal], start: &str, end: &str) -> Result<String, MalErr> {
+    let strs: Vec<String> = seq.iter().map(|x| x.pr_edn()).collect::<Result<_, _>>()?;
+    Ok(format!("{}{}{}", start, strs.join(" "), end))
+}
+
+impl MalVal {
+    // Like pr_str(true), but functions and atoms have no EDN form and
+    // are rejected rather than printed.
+    pub fn pr_edn(&self) -> Result<String, MalErr> {
+        match self {
+            List(l, _) => pr_edn_seq(l, "(", ")"),
+            Vector(l, _) => pr_edn_seq(l, "[", "]"),
+            Hash(hm, _) => {
+                let l: Vec<MalVal> = hm
+                    .iter()
+                    .sorted_by(|(k1, _), (k2, _)| k1.cmp(k2))
+                    .flat_map(|(k, v)| vec![key_val(k), v.clone()])
+                    .collect();
+                pr_edn_seq(&l, "{", "}")
+            }
+            SortedMap(t, _) => {
+                let l: Vec<MalVal> = t
+                    .entries()
+                    .into_iter()
+                    .flat_map(|(k, v)| vec![k, v])
+                    .collect();
+                pr_edn_seq(&l, "{", "}")
+            }
+            SortedSet(t, _) => pr_edn_seq(&t.keys(), "#{", "}"),
+            Func(_, _) | MalFunc { .. } | Atom(_) => Err(ErrString(format!(
+                "edn: cannot print {}",
+                self.pr_str(true)
+            ))),
+            _ => Ok(self.pr_str(true)),
+        }
+    }
+}
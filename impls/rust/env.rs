@@ -1,25 +1,68 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 //use std::collections::HashMap;
 use fnv::FnvHashMap;
 
+use crate::symbol::Symbol;
 use crate::types::MalErr::ErrString;
 use crate::types::MalVal::{List, Nil, Sym, Vector};
 use crate::types::{error, MalErr, MalRet, MalVal};
 
 pub struct EnvStruct {
-    data: RefCell<FnvHashMap<String, MalVal>>,
+    data: RefCell<FnvHashMap<Symbol, MalVal>>,
+    // Locals of a compiled fn*, let* or catch*, which the compiler
+    // addresses by (depth, slot) rather than by name. Slot i holds the
+    // value of layout[i]; a let* fills its slots in order.
+    layout: Option<Layout>,
+    slots: RefCell<Vec<MalVal>>,
     pub outer: Option<Env>,
 }
 
 pub type Env = Rc<EnvStruct>;
 
+// The names of the slots of an env
+pub type Layout = Rc<Vec<Symbol>>;
+
+thread_local! {
+    static LIVE: Cell<usize> = const { Cell::new(0) };
+    static MADE: Cell<usize> = const { Cell::new(0) };
+}
+
+fn count_env() {
+    LIVE.with(|n| n.set(n.get() + 1));
+    MADE.with(|n| n.set(n.get() + 1));
+}
+
+impl Drop for EnvStruct {
+    fn drop(&mut self) {
+        LIVE.with(|n| n.set(n.get() - 1));
+    }
+}
+
+// The number of envs alive, and made so far
+pub fn env_counts() -> (usize, usize) {
+    (LIVE.with(|n| n.get()), MADE.with(|n| n.get()))
+}
+
 // TODO: it would be nice to use impl here but it doesn't work on
 // a deftype (i.e. Env)
 
 pub fn env_new(outer: Option<Env>) -> Env {
+    count_env();
+    Rc::new(EnvStruct {
+        data: RefCell::new(FnvHashMap::default()),
+        layout: None,
+        slots: RefCell::new(vec![]),
+        outer,
+    })
+}
+
+pub fn env_new_local(outer: Option<Env>, layout: &Layout) -> Env {
+    count_env();
     Rc::new(EnvStruct {
         data: RefCell::new(FnvHashMap::default()),
+        slots: RefCell::new(Vec::with_capacity(layout.len())),
+        layout: Some(layout.clone()),
         outer,
     })
 }
@@ -46,10 +89,74 @@ pub fn env_bind(outer: Option<Env>, mbinds: &MalVal, exprs: Vec<MalVal>) -> Resu
     }
 }
 
-pub fn env_get(env: &Env, key: &str) -> Option<MalVal> {
+// Binds the args of a call to a compiled function. When variadic,
+// the last slot gets a list of the remaining args.
+pub fn env_bind_local(
+    outer: Env,
+    layout: &Layout,
+    variadic: bool,
+    mut args: Vec<MalVal>,
+) -> Result<Env, MalErr> {
+    let fixed = if variadic {
+        layout.len() - 1
+    } else {
+        layout.len()
+    };
+    if args.len() < fixed {
+        return Err(ErrString(format!(
+            "expected {} arguments, got {}",
+            fixed,
+            args.len()
+        )));
+    }
+    if variadic {
+        let rest = args.split_off(fixed);
+        args.push(list!(rest));
+    } else {
+        args.truncate(fixed);
+    }
+    count_env();
+    Ok(Rc::new(EnvStruct {
+        data: RefCell::new(FnvHashMap::default()),
+        layout: Some(layout.clone()),
+        slots: RefCell::new(args),
+        outer: Some(outer),
+    }))
+}
+
+// The slot that holds key, if it has been bound yet
+fn env_slot(env: &EnvStruct, key: Symbol) -> Option<usize> {
+    let layout = env.layout.as_ref()?;
+    let bound = env.slots.borrow().len();
+    layout[..bound].iter().position(|n| *n == key)
+}
+
+pub fn env_get_local(env: &Env, depth: usize, slot: usize) -> MalVal {
+    let mut mut_env = env;
+    for _ in 0..depth {
+        mut_env = mut_env
+            .outer
+            .as_ref()
+            .expect("local depth beyond the outermost env");
+    }
+    mut_env.slots.borrow()[slot].clone()
+}
+
+pub fn env_set_local(env: &Env, slot: usize, val: MalVal) {
+    let mut slots = env.slots.borrow_mut();
+    if slot == slots.len() {
+        slots.push(val);
+    } else {
+        slots[slot] = val;
+    }
+}
+
+pub fn env_get(env: &Env, key: Symbol) -> Option<MalVal> {
     let mut mut_env = env;
     loop {
-        if let Some(value) = mut_env.data.borrow().get(key) {
+        if let Some(slot) = env_slot(mut_env, key) {
+            return Some(mut_env.slots.borrow()[slot].clone());
+        } else if let Some(value) = mut_env.data.borrow().get(&key) {
             return Some(value.clone());
         } else if let Some(outer) = &mut_env.outer {
             mut_env = outer;
@@ -59,6 +166,47 @@ pub fn env_get(env: &Env, key: &str) -> Option<MalVal> {
     }
 }
 
+// Bindings of this environment only, not of its outer ones.
+pub fn env_entries(env: &Env) -> Vec<(Symbol, MalVal)> {
+    let mut entries: Vec<(Symbol, MalVal)> = env
+        .data
+        .borrow()
+        .iter()
+        .map(|(k, v)| (*k, v.clone()))
+        .collect();
+    if let Some(layout) = &env.layout {
+        let slots = env.slots.borrow();
+        entries.extend(layout.iter().cloned().zip(slots.iter().cloned()));
+    }
+    entries
+}
+
+// Calls f with each value bound in this environment, without cloning
+// them. Returns false, having done nothing, if the bindings are being
+// changed.
+pub fn env_each_value(env: &Env, mut f: impl FnMut(&MalVal)) -> bool {
+    match (env.data.try_borrow(), env.slots.try_borrow()) {
+        (Ok(data), Ok(slots)) => {
+            data.values().chain(slots.iter()).for_each(&mut f);
+            true
+        }
+        _ => false,
+    }
+}
+
+// Moves the values of all bindings of this environment to vals, or
+// returns false if they are in use.
+pub fn env_clear(env: &Env, vals: &mut Vec<MalVal>) -> bool {
+    match (env.data.try_borrow_mut(), env.slots.try_borrow_mut()) {
+        (Ok(mut data), Ok(mut slots)) => {
+            vals.extend(data.drain().map(|(_, v)| v));
+            vals.append(&mut slots);
+            true
+        }
+        _ => false,
+    }
+}
+
 pub fn env_find_repl(env: &Env) -> Env {
     let mut mut_env = env;
     while let Some(outer) = &mut_env.outer {
@@ -70,13 +218,18 @@ pub fn env_find_repl(env: &Env) -> Env {
 pub fn env_set(env: &Env, key: &MalVal, val: MalVal) -> MalRet {
     match key {
         Sym(s) => {
-            env_sets(env, s, val.clone());
+            env_sets(env, *s, val.clone());
             Ok(val)
         }
         _ => error("Env.set called with non-Str"),
     }
 }
 
-pub fn env_sets(env: &Env, key: &str, val: MalVal) {
-    env.data.borrow_mut().insert(key.to_string(), val);
+pub fn env_sets(env: &Env, key: Symbol, val: MalVal) {
+    match env_slot(env, key) {
+        Some(slot) => env.slots.borrow_mut()[slot] = val,
+        None => {
+            env.data.borrow_mut().insert(key, val);
+        }
+    }
 }
@@ -0,0 +1,290 @@
+use std::any::Any;
+use std::rc::Rc;
+//use std::collections::HashMap;
+use fnv::FnvHashMap;
+
+use crate::compile::{compile, Lambda, Node, Op};
+use crate::cover;
+use crate::env::{
+    env_bind_local, env_get, env_get_local, env_new_local, env_set_local, env_sets, Env,
+};
+use crate::gc;
+use crate::limits;
+use crate::profile;
+use crate::symbol::Symbol;
+use crate::trace;
+use crate::types::MalErr::{ErrMalVal, ErrString};
+use crate::types::MalVal::{Bool, Func, List, MalFunc, Nil, Str, Sym, Vector};
+use crate::types::{
+    count_call, count_eval, enter_call, error, leave_call, new_hash, Compiled, Context, FnCount,
+    MalArgs, MalErr, MalRet, MalVal,
+};
+
+impl Compiled for Lambda {
+    fn call(self: Rc<Self>, env: &Env, args: MalArgs) -> MalRet {
+        let env = env_bind_local(env.clone(), &self.layout, self.variadic, args)?;
+        enter_call()?;
+        let res = exec(&self.body, &env);
+        leave_call();
+        res
+    }
+
+    fn as_any(self: Rc<Self>) -> Rc<dyn Any> {
+        self
+    }
+
+    fn name(&self) -> Option<Symbol> {
+        self.name.get()
+    }
+
+    fn set_name(&self, name: Symbol) {
+        self.name.set(Some(name))
+    }
+}
+
+pub fn clone_err(e: &MalErr) -> MalErr {
+    match e {
+        ErrString(s) => ErrString(s.to_string()),
+        ErrMalVal(mv) => ErrMalVal(mv.clone()),
+    }
+}
+
+// The value try* binds in catch* for an error
+pub fn exception(e: MalErr) -> MalVal {
+    match e {
+        ErrMalVal(mv) => mv,
+        ErrString(s) => Str(s),
+    }
+}
+
+// Compiles ast and runs it. The forms of a top-level do are compiled
+// one at a time, so that macros defined by one form are expanded in
+// the forms after it.
+pub fn eval(ast: &MalVal, env: &Env) -> MalRet {
+    if let List(l, _) = ast {
+        if l.len() > 1 && matches!(l[0], Sym(ref s) if s == "do") {
+            let mut res = Nil;
+            for form in l[1..].iter() {
+                res = eval(form, env)?;
+            }
+            return Ok(res);
+        }
+    }
+    count_eval();
+    let node = compile(ast, env);
+    // calls are traced by exec
+    if trace::hooked() && !is_call(&node) {
+        trace::enter(ast, env)?;
+        let res = exec(&node, env);
+        trace::leave(&res);
+        return res;
+    }
+    exec(&node, env)
+}
+
+fn is_call(node: &Node) -> bool {
+    match &node.op {
+        Op::Call(..) => true,
+        Op::Cover(_, n) => is_call(n),
+        _ => false,
+    }
+}
+
+pub fn exec(node: &Node, env: &Env) -> MalRet {
+    if trace::hooked() && is_call(node) {
+        trace::enter(&node.form, env)?;
+        let res = run(node, env);
+        trace::leave(&res);
+        return res;
+    }
+    run(node, env)
+}
+
+fn run(start: &Node, start_env: &Env) -> MalRet {
+    let mut profiled = false;
+    let mut entered = false;
+    let res = run_calls(start, start_env, &mut profiled, &mut entered);
+    if profiled {
+        profile::leave();
+    }
+    if entered {
+        leave_call();
+    }
+    res
+}
+
+// profiled is set once a call made here has been reported to the
+// profiler, after which tail calls replace it, and entered once one
+// has been counted as nesting.
+fn run_calls(start: &Node, start_env: &Env, profiled: &mut bool, entered: &mut bool) -> MalRet {
+    let mut node = start;
+    let mut env = start_env;
+    // These variables ensure a sufficient lifetime for the data
+    // referenced by node and env.
+    let mut live_node: Rc<Node>;
+    let mut live_env;
+
+    loop {
+        limits::step()?;
+        if trace::active() {
+            trace::debug_eval(&node.form, env);
+        }
+        match &node.op {
+            Op::Const(v) => return Ok(v.clone()),
+            Op::Sym(s) => {
+                return env_get(env, *s).ok_or_else(|| ErrString(format!("'{}' not found", s)))
+            }
+            Op::Local(depth, slot) => return Ok(env_get_local(env, *depth, *slot)),
+            Op::Vector(items) => {
+                let v = items
+                    .iter()
+                    .map(|n| exec(n, env))
+                    .collect::<Result<MalArgs, MalErr>>()?;
+                return Ok(vector!(v));
+            }
+            Op::Hash(entries) => {
+                let mut hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+                for (k, n) in entries.iter() {
+                    hm.insert(k.to_string(), exec(n, env)?);
+                }
+                return Ok(new_hash(hm));
+            }
+            Op::Def(name, val) => {
+                let v = exec(val, env)?;
+                profile::name_fn(&v, *name);
+                env_sets(env, *name, v.clone());
+                return Ok(v);
+            }
+            Op::DefMacro(name, val) => match exec(val, env)? {
+                MalFunc {
+                    eval,
+                    ast,
+                    env: fenv,
+                    params,
+                    code,
+                    ..
+                } => {
+                    let m = MalFunc {
+                        eval,
+                        ast,
+                        env: fenv,
+                        params,
+                        is_macro: true,
+                        meta: Rc::new(Nil),
+                        code,
+                        counted: FnCount::new(),
+                    };
+                    profile::name_fn(&m, *name);
+                    env_sets(env, *name, m.clone());
+                    return Ok(m);
+                }
+                _ => return error("set_macro on non-function"),
+            },
+            Op::Let(layout, bindings, body) => {
+                live_env = env_new_local(Some(env.clone()), layout);
+                env = &live_env;
+                for (slot, val) in bindings.iter() {
+                    let v = exec(val, env)?;
+                    env_set_local(env, *slot, v);
+                }
+                live_node = body.clone();
+                node = &live_node;
+            }
+            Op::Do(forms, last) => {
+                for f in forms.iter() {
+                    exec(f, env)?;
+                }
+                live_node = last.clone();
+                node = &live_node;
+            }
+            Op::If(cond, then, otherwise) => {
+                live_node = match exec(cond, env)? {
+                    Bool(false) | Nil => otherwise.clone(),
+                    _ => then.clone(),
+                };
+                node = &live_node;
+            }
+            Op::Fn(lambda) => {
+                gc::track(env);
+                let f = MalFunc {
+                    eval,
+                    ast: lambda.ast.clone(),
+                    env: env.clone(),
+                    params: lambda.params.clone(),
+                    is_macro: false,
+                    meta: Rc::new(Nil),
+                    code: Some(lambda.clone()),
+                    counted: FnCount::new(),
+                };
+                return Ok(f);
+            }
+            Op::Try(body, layout, handler) => match exec(body, env) {
+                Err(e) => {
+                    live_env = env_new_local(Some(env.clone()), layout);
+                    env = &live_env;
+                    env_set_local(env, 0, exception(e));
+                    live_node = handler.clone();
+                    node = &live_node;
+                }
+                res => return res,
+            },
+            Op::Error(e) => return Err(clone_err(e)),
+            Op::Cover(site, n) => {
+                cover::hit(*site);
+                live_node = n.clone();
+                node = &live_node;
+            }
+            Op::Call(fnode, args) => {
+                let f = exec(fnode, env)?;
+                if let MalFunc { is_macro: true, .. } = f {
+                    // a macro that was not defined yet when the call
+                    // was compiled
+                    let margs = match node.form {
+                        List(ref l, _) => l[1..].to_vec(),
+                        _ => vec![],
+                    };
+                    live_node = compile(&f.apply(margs)?, env);
+                    node = &live_node;
+                    continue;
+                }
+                let args = args
+                    .iter()
+                    .map(|a| exec(a, env))
+                    .collect::<Result<MalArgs, MalErr>>()?;
+                count_call();
+                let body = match f {
+                    Func(_, _) => return f.apply_ctx(args, Some(&Context { env, eval })),
+                    MalFunc {
+                        code: Some(ref code),
+                        ..
+                    } => code.clone().as_any().downcast::<Lambda>(),
+                    MalFunc { .. } => return f.apply(args),
+                    _ => return error("attempt to call non-function"),
+                };
+                match (body, &f) {
+                    (Ok(lambda), MalFunc { env: menv, .. }) => {
+                        if profile::enabled() {
+                            if *profiled {
+                                profile::replace(&f);
+                            } else {
+                                profile::enter(&f);
+                                *profiled = true;
+                            }
+                        }
+                        if !*entered {
+                            enter_call()?;
+                            *entered = true;
+                        }
+                        live_env =
+                            env_bind_local(menv.clone(), &lambda.layout, lambda.variadic, args)?;
+                        env = &live_env;
+                        live_node = lambda.body.clone();
+                        node = &live_node;
+                    }
+                    // compiled by some other evaluator
+                    _ => return f.apply(args),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use std::cell::{Cell, RefCell};
+use std::mem;
+use std::rc::{Rc, Weak};
+
+use fnv::FnvHashMap;
+
+use crate::env::{env_clear, env_each_value, Env, EnvStruct};
+use crate::types::MalVal;
+use crate::types::MalVal::{Atom, Hash, List, MalFunc, Vector};
+
+// A collector for the reference cycles that closures make, e.g. a
+// function bound in the env it closes over. Every such cycle passes
+// through an env that a closure was made in, so those envs are
+// tracked, and a collection looks at everything reachable from them.
+//
+// Collection is by trial deletion: the references found inside that
+// graph are subtracted from the reference counts, and whatever is not
+// reachable from a node with references left over is only referred to
+// by other garbage. Its envs and atoms are then emptied, which breaks
+// the cycles and lets the reference counts free it. Anything the
+// collector cannot look inside (sorted collections, metadata, compiled
+// code) counts as a reference from outside, so it only keeps more
+// alive than it has to.
+
+// Collect once this many more envs are tracked than after the last
+// collection, or twice as many if that is more.
+const MIN_GROWTH: usize = 10000;
+
+thread_local! {
+    static TRACKED: RefCell<Vec<Weak<EnvStruct>>> = const { RefCell::new(vec![]) };
+    static THRESHOLD: Cell<usize> = const { Cell::new(MIN_GROWTH) };
+    static TOTALS: Cell<GcStats> = Cell::new(GcStats::default());
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GcStats {
+    pub collections: usize,
+    // envs whose closures may be in a cycle, after collecting
+    pub tracked: usize,
+    pub freed_envs: usize,
+    pub freed_atoms: usize,
+}
+
+impl GcStats {
+    pub fn entries(&self) -> [(&'static str, usize); 4] {
+        [
+            ("gc-runs", self.collections),
+            ("tracked-envs", self.tracked),
+            ("freed-envs", self.freed_envs),
+            ("freed-atoms", self.freed_atoms),
+        ]
+    }
+}
+
+// Called for the env each closure is made in
+pub fn track(env: &Env) {
+    let len = TRACKED.with(|t| {
+        let mut t = t.borrow_mut();
+        // e.g. closures made in a loop
+        if t.last().is_some_and(|w| w.as_ptr() == Rc::as_ptr(env)) {
+            return 0;
+        }
+        t.push(Rc::downgrade(env));
+        t.len()
+    });
+    if len > THRESHOLD.with(|t| t.get()) {
+        collect();
+    }
+}
+
+// Totals over all collections so far
+pub fn totals() -> GcStats {
+    TOTALS.with(|t| t.get())
+}
+
+enum Node {
+    Env(Env),
+    Seq(Rc<Vec<MalVal>>),
+    Map(Rc<FnvHashMap<String, MalVal>>),
+    Atom(Rc<RefCell<MalVal>>),
+}
+
+impl Node {
+    fn addr(&self) -> usize {
+        match self {
+            Node::Env(e) => Rc::as_ptr(e) as usize,
+            Node::Seq(s) => Rc::as_ptr(s) as usize,
+            Node::Map(m) => Rc::as_ptr(m) as usize,
+            Node::Atom(a) => Rc::as_ptr(a) as usize,
+        }
+    }
+
+    // less the reference held by the node itself
+    fn refs(&self) -> usize {
+        match self {
+            Node::Env(e) => Rc::strong_count(e) - 1,
+            Node::Seq(s) => Rc::strong_count(s) - 1,
+            Node::Map(m) => Rc::strong_count(m) - 1,
+            Node::Atom(a) => Rc::strong_count(a) - 1,
+        }
+    }
+}
+
+fn child(v: &MalVal) -> Option<Node> {
+    match v {
+        List(l, _) | Vector(l, _) => Some(Node::Seq(l.clone())),
+        Hash(m, _) => Some(Node::Map(m.clone())),
+        Atom(a) => Some(Node::Atom(a.clone())),
+        MalFunc { env, .. } => Some(Node::Env(env.clone())),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+struct Graph {
+    nodes: Vec<Node>,
+    index: FnvHashMap<usize, usize>,
+    // the nodes each node refers to, in the order they were expanded
+    edges: Vec<usize>,
+    first_edge: Vec<usize>,
+    // references from inside the graph
+    internal: Vec<usize>,
+    // nodes that could not be looked inside, being in use
+    pinned: Vec<bool>,
+}
+
+impl Graph {
+    fn add(&mut self, n: Node) -> usize {
+        let addr = n.addr();
+        if let Some(i) = self.index.get(&addr) {
+            return *i;
+        }
+        self.nodes.push(n);
+        self.internal.push(0);
+        self.pinned.push(false);
+        self.index.insert(addr, self.nodes.len() - 1);
+        self.nodes.len() - 1
+    }
+
+    // Finds the nodes that node i refers to
+    fn expand(&mut self, i: usize, children: &mut Vec<Node>) {
+        self.first_edge.push(self.edges.len());
+        let ok = match &self.nodes[i] {
+            Node::Env(e) => {
+                children.extend(e.outer.clone().map(Node::Env));
+                env_each_value(e, |v| children.extend(child(v)))
+            }
+            Node::Seq(s) => {
+                children.extend(s.iter().filter_map(child));
+                true
+            }
+            Node::Map(m) => {
+                children.extend(m.values().filter_map(child));
+                true
+            }
+            Node::Atom(a) => match a.try_borrow() {
+                Ok(v) => {
+                    children.extend(child(&v));
+                    true
+                }
+                Err(_) => false,
+            },
+        };
+        if !ok {
+            self.pinned[i] = true;
+            return;
+        }
+        for c in children.drain(..) {
+            let j = self.add(c);
+            self.internal[j] += 1;
+            self.edges.push(j);
+        }
+    }
+
+    fn edges(&self, i: usize) -> &[usize] {
+        let end = self.first_edge.get(i + 1).copied();
+        &self.edges[self.first_edge[i]..end.unwrap_or(self.edges.len())]
+    }
+}
+
+// Frees the cycles that no longer have any references from outside
+// them, and returns what was freed.
+pub fn collect() -> GcStats {
+    let roots: Vec<Env> = TRACKED.with(|t| {
+        let mut t = t.borrow_mut();
+        t.retain(|w| w.strong_count() > 0);
+        t.iter().filter_map(|w| w.upgrade()).collect()
+    });
+
+    let mut g = Graph::default();
+    for env in roots {
+        g.add(Node::Env(env));
+    }
+    let mut children = vec![];
+    let mut i = 0;
+    while i < g.nodes.len() {
+        g.expand(i, &mut children);
+        i += 1;
+    }
+
+    let mut live = vec![false; g.nodes.len()];
+    let mut todo: Vec<usize> = (0..g.nodes.len())
+        .filter(|&i| g.pinned[i] || g.nodes[i].refs() > g.internal[i])
+        .collect();
+    while let Some(i) = todo.pop() {
+        if !live[i] {
+            live[i] = true;
+            todo.extend(g.edges(i).iter().filter(|&&j| !live[j]));
+        }
+    }
+
+    let mut stats = GcStats::default();
+    // dropped once the graph's own references are gone
+    let mut garbage: Vec<MalVal> = vec![];
+    for (i, n) in g.nodes.iter().enumerate() {
+        if live[i] {
+            continue;
+        }
+        match n {
+            Node::Env(e) if env_clear(e, &mut garbage) => stats.freed_envs += 1,
+            Node::Atom(a) => {
+                if let Ok(mut v) = a.try_borrow_mut() {
+                    garbage.push(mem::replace(&mut *v, MalVal::Nil));
+                    stats.freed_atoms += 1;
+                }
+            }
+            _ => (),
+        }
+    }
+    drop(g);
+    drop(garbage);
+
+    stats.collections = 1;
+    stats.tracked = TRACKED.with(|t| {
+        let mut t = t.borrow_mut();
+        t.retain(|w| w.strong_count() > 0);
+        t.len()
+    });
+    THRESHOLD.with(|t| t.set((stats.tracked + MIN_GROWTH).max(stats.tracked * 2)));
+    TOTALS.with(|t| {
+        let mut total = t.get();
+        total.collections += 1;
+        total.tracked = stats.tracked;
+        total.freed_envs += stats.freed_envs;
+        total.freed_atoms += stats.freed_atoms;
+        t.set(total)
+    });
+    stats
+}
@@ -0,0 +1,315 @@
+use std::cell::Cell;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::convert::{typed_builtin, TypedFn};
+use crate::core;
+use crate::cover;
+use crate::debug;
+use crate::env::{env_find_repl, env_get, env_new, env_sets, Env};
+use crate::eval;
+use crate::gc;
+use crate::limits::{self, Interrupt, Limits};
+use crate::reader::read_str;
+use crate::sandbox::{self, Sandbox, Sink};
+use crate::symbol::intern;
+use crate::trace;
+use crate::types::MalErr::ErrString;
+use crate::types::MalVal::{Bool, Int, List, Nil, Str};
+use crate::types::{
+    builtin, builtin_ctx, error, new_hash, runtime_stats, with_fallback_context, Arity, Context,
+    EvalFn, MalArgs, MalErr, MalRet, MalVal,
+};
+use crate::vm;
+
+// core.mal: defined using the language itself
+const PRELUDE: &[&str] = &[
+    "(def! *host-language* \"rust\")",
+    "(def! not (fn* (a) (if a false true)))",
+    "(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))",
+];
+
+// How an Interpreter evaluates forms: by walking the compiled node
+// tree, or by lowering it further to bytecode for the VM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Engine {
+    Tree,
+    Bytecode,
+}
+
+// A mal interpreter with its own REPL environment, for embedding in
+// Rust programs. Its limits and whether it traces and debugs are its
+// own too, and are put in place for the thread while it evaluates.
+// Profiling, coverage, cycle collection and the runtime stats are not:
+// they see whatever runs on the thread, so they are functions of the
+// crate rather than methods.
+pub struct Interpreter {
+    // to tell whose evaluation is running
+    id: usize,
+    env: Env,
+    eval: EvalFn,
+    trace: Cell<bool>,
+    debug: Cell<bool>,
+    limits: Cell<Limits>,
+    interrupt: Interrupt,
+    // where a sandbox sends what is printed
+    sink: Option<Rc<Sink>>,
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Interpreter::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        Interpreter::with_engine(Engine::Tree)
+    }
+
+    pub fn with_engine(engine: Engine) -> Interpreter {
+        Interpreter::build(engine, None)
+    }
+
+    // An interpreter whose programs can only get at files and the
+    // terminal as far as the sandbox allows
+    pub fn sandboxed(engine: Engine, sandbox: Sandbox) -> Interpreter {
+        Interpreter::build(engine, Some(Rc::new(sandbox)))
+    }
+
+    fn build(engine: Engine, sandbox: Option<Rc<Sandbox>>) -> Interpreter {
+        let eval = match engine {
+            Engine::Tree => eval::eval,
+            Engine::Bytecode => vm::eval,
+        };
+        let env = env_new(None);
+        let sink = sandbox.as_ref().map(|sb| Rc::new(Sink::new(sb)));
+        // core.rs: defined using rust
+        for (k, v) in core::ns() {
+            if sandbox.is_none() || !sandbox::IO.contains(&k) {
+                env_sets(&env, intern(k), v);
+            }
+        }
+        if let (Some(sb), Some(sink)) = (&sandbox, &sink) {
+            for (k, v) in sandbox::ns(sb, sink) {
+                env_sets(&env, intern(k), v);
+            }
+        }
+        env_sets(&env, intern("*ARGV*"), list![]);
+        let interp = Interpreter {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            env,
+            eval,
+            trace: Cell::new(false),
+            debug: Cell::new(false),
+            limits: Cell::new(Limits::default()),
+            interrupt: Interrupt::default(),
+            sink,
+        };
+        // these use the terminal
+        if sandbox.is_none() {
+            interp.register_fn("set-trace!", Arity::Exactly(1), |a| {
+                trace::set_trace(!matches!(a[0], Bool(false) | Nil));
+                Ok(Nil)
+            });
+            interp.define(
+                "break",
+                builtin_ctx("break", Arity::Exactly(0), debug::break_fn),
+            );
+        }
+        interp.register_fn("gc", Arity::Exactly(0), |_| {
+            let stats = gc::collect();
+            Ok(stats_map(&[
+                ("freed-envs", stats.freed_envs),
+                ("freed-atoms", stats.freed_atoms),
+                ("tracked-envs", stats.tracked),
+            ]))
+        });
+        interp.register_fn("runtime-stats", Arity::Exactly(0), |_| {
+            Ok(stats_map(&runtime_stats().entries()))
+        });
+        interp.define(
+            "load-file",
+            builtin_ctx("load-file", Arity::Exactly(1), move |a, ctx| {
+                load_file(a, ctx, sandbox.as_deref())
+            }),
+        );
+        for src in PRELUDE {
+            if let Err(e) = interp.eval_str(src) {
+                panic!("error during startup: {}", e);
+            }
+        }
+        interp
+    }
+
+    pub fn env(&self) -> &Env {
+        &self.env
+    }
+
+    // Evaluates every form in src and returns the value of the last one.
+    pub fn eval_str(&self, src: &str) -> Result<MalVal, MalErr> {
+        let ast = read_str(&format!("(do {}\n)", src))?;
+        self.evaluation(|| (self.eval)(&ast, &self.env))
+    }
+
+    pub fn eval_file(&self, path: &str) -> Result<MalVal, MalErr> {
+        let mut src = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut src))
+            .map_err(|e| ErrString(format!("{}: {}", path, e)))?;
+        self.eval_str(&src)
+    }
+
+    // Evaluates a single form and returns it printed readably, as the
+    // REPL does.
+    pub fn rep(&self, line: &str) -> Result<String, MalErr> {
+        let ast = read_str(line)?;
+        let res = self.evaluation(|| (self.eval)(&ast, &self.env))?;
+        Ok(res.pr_str(true))
+    }
+
+    pub fn define(&self, name: &str, val: MalVal) {
+        let name = intern(name);
+        trace::note_binding(name);
+        env_sets(&self.env, name, val);
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<MalVal> {
+        env_get(&self.env, intern(name))
+    }
+
+    pub fn call(&self, name: &str, args: MalArgs) -> MalRet {
+        let f = match self.lookup(name) {
+            Some(f) => f,
+            None => return Err(ErrString(format!("'{}' not found", name))),
+        };
+        let ctx = Context {
+            env: &self.env,
+            eval: self.eval,
+        };
+        self.evaluation(|| f.apply_ctx(args, Some(&ctx)))
+    }
+
+    // Runs f with this interpreter's settings in place of the thread's,
+    // keeping any change it makes to them, e.g. with set-trace!
+    fn evaluation<T>(&self, f: impl FnOnce() -> T) -> T {
+        if limits::evaluating(self.id) {
+            return f();
+        }
+        let trace = trace::swap_trace(self.trace.get());
+        let (debug, eval) = debug::swap_debug(self.debug.get(), Some(self.eval));
+        let res = sandbox::with_sink(self.sink.as_ref(), || {
+            with_fallback_context(&self.env, self.eval, || {
+                limits::evaluation(self.id, self.limits.get(), &self.interrupt, f)
+            })
+        });
+        self.trace.set(trace::swap_trace(trace));
+        self.debug.set(debug::swap_debug(debug, eval).0);
+        res
+    }
+
+    pub fn register_fn<F>(&self, name: &str, arity: Arity, f: F)
+    where
+        F: Fn(MalArgs) -> MalRet + 'static,
+    {
+        self.define(name, builtin(name, arity, f));
+    }
+
+    // Registers a Rust function with typed arguments, e.g.
+    // `interp.register_typed("add", |a: i64, b: i64| a + b)`.
+    pub fn register_typed<Args, F>(&self, name: &str, f: F)
+    where
+        F: TypedFn<Args> + 'static,
+    {
+        self.define(name, typed_builtin(name, f));
+    }
+
+    // Prints each top-level form and function call this interpreter
+    // evaluates, and its result, to stderr, as `(set-trace! true)` does.
+    pub fn set_trace(&self, on: bool) {
+        self.trace.set(on);
+        if limits::evaluating(self.id) {
+            trace::set_trace(on);
+        }
+    }
+
+    // Makes `(break)` stop in a nested REPL on stdin, from which the
+    // program can be inspected and stepped. Without it `(break)` does
+    // nothing.
+    pub fn set_debug(&self, on: bool) {
+        self.debug.set(on);
+        if limits::evaluating(self.id) {
+            debug::swap_debug(on, Some(self.eval));
+        }
+    }
+
+    // Limits each evaluation started from here after this, e.g. by
+    // eval_str or call, to so many steps, so much time and collections
+    // of up to so many elements. An evaluation that goes over one fails
+    // with an error for which limits::is_exceeded is true.
+    pub fn set_limits(&self, limits: Limits) {
+        self.limits.set(limits)
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits.get()
+    }
+
+    // A handle to stop this interpreter's evaluations from any thread,
+    // with an "interrupted" error
+    pub fn interrupt(&self) -> Interrupt {
+        self.interrupt.clone()
+    }
+
+    // The output of prn, println and DEBUG-EVAL captured by a sandbox
+    // since the last call
+    pub fn take_output(&self) -> String {
+        self.sink.as_ref().map_or(String::new(), |sink| sink.take())
+    }
+
+    pub fn set_argv(&self, args: Vec<String>) {
+        let argv = args.into_iter().map(Str).collect();
+        self.define("*ARGV*", list!(argv));
+    }
+}
+
+// Like `(eval (read-string (str "(do " (slurp f) "\nnil)")))`, but
+// reading the file itself so that coverage knows where forms came from.
+fn load_file(a: MalArgs, ctx: &Context, sandbox: Option<&Sandbox>) -> MalRet {
+    let path = match a[0] {
+        Str(ref s) => s,
+        _ => return error("load-file: expecting a string"),
+    };
+    let src = match sandbox {
+        Some(sb) => sb.read(path)?,
+        None => {
+            let mut src = String::new();
+            if let Err(e) = File::open(path).and_then(|mut f| f.read_to_string(&mut src)) {
+                return error(&e.to_string());
+            }
+            src
+        }
+    };
+    let src = format!("(do {}\nnil)", src);
+    let ast = if cover::enabled() {
+        let path = fs::canonicalize(path).map_or(path.to_string(), |p| p.display().to_string());
+        cover::read_file(&path, &src)?
+    } else {
+        read_str(&src)?
+    };
+    (ctx.eval)(&ast, &env_find_repl(ctx.env))
+}
+
+// A hash-map of counts with keyword keys
+fn stats_map(entries: &[(&str, usize)]) -> MalVal {
+    let hm = entries
+        .iter()
+        .map(|(k, v)| (format!("\u{29e}{}", k), Int(*v as i64)))
+        .collect();
+    new_hash(hm)
+}
@@ -0,0 +1,41 @@
+#[macro_use]
+extern crate lazy_static;
+extern crate fnv;
+extern crate itertools;
+extern crate regex;
+extern crate rustyline;
+extern crate serde;
+extern crate serde_json;
+
+#[macro_use]
+pub mod types;
+pub mod bytecode;
+pub mod compile;
+pub mod convert;
+pub mod core;
+pub mod cover;
+pub mod debug;
+pub mod edn;
+pub mod env;
+pub mod eval;
+pub mod gc;
+pub mod interpreter;
+pub mod limits;
+pub mod printer;
+pub mod profile;
+pub mod reader;
+pub mod sandbox;
+pub mod serde_mal;
+pub mod sorted;
+pub mod symbol;
+pub mod trace;
+pub mod vm;
+
+pub use crate::convert::{FromMal, IntoMal};
+pub use crate::cover::{lcov as coverage_lcov, set_coverage};
+pub use crate::gc::{collect as gc, totals as gc_totals, GcStats};
+pub use crate::interpreter::{Engine, Interpreter};
+pub use crate::limits::{Interrupt, Limits};
+pub use crate::profile::{folded as profile_folded, report as profile_report, set_profile};
+pub use crate::sandbox::{Output, Sandbox};
+pub use crate::types::{runtime_stats, Arity, MalArgs, MalErr, MalRet, MalVal, RuntimeStats};
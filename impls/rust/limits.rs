@@ -0,0 +1,237 @@
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::types::MalErr::ErrString;
+use crate::types::MalVal::{Hash, List, SortedMap, SortedSet, Str, Vector};
+use crate::types::{MalErr, MalRet};
+
+// Limits on what a single evaluation may do, for running code that
+// cannot be trusted to finish. The evaluators count a step for each
+// node of the tree or instruction of the VM they run, including those
+// of the functions that builtins like map call, and every so many
+// steps they look at the clock and at whether they were interrupted.
+// The builtins that make collections or strings check the size of what
+// they make, and calls how deeply they are nested, as going too deep
+// would overflow the stack of the tree evaluator.
+//
+// Going over a limit raises a "limit exceeded" error. try* can catch
+// it, but every step after that raises it again, so the evaluation
+// still ends. The same goes for the "interrupted" error an evaluation
+// gets once the Interrupt of its interpreter is used, e.g. from a
+// signal handler.
+//
+// Apart from that, which is for any thread to use, the state is per
+// thread. Each evaluation runs with the limits of the interpreter that
+// started it, which are put back to those of any evaluation it is
+// nested in when it ends.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub max_steps: Option<usize>,
+    pub timeout: Option<Duration>,
+    // elements of a list, vector, map or set, or bytes of a string
+    pub max_size: Option<usize>,
+    // calls nested in one another, not counting tail calls
+    pub max_depth: Option<usize>,
+}
+
+thread_local! {
+    static LIMITS: Cell<Limits> = Cell::new(Limits::default());
+    // the steps taken, and the step at which to look at the limits next
+    static STEPS: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+    // why every step fails from now on, if it does
+    static STOPPED: Cell<Option<Stop>> = const { Cell::new(None) };
+    // the interpreter whose evaluation is running, and its Interrupt
+    static OWNER: Cell<Option<usize>> = const { Cell::new(None) };
+    static INTERRUPT: RefCell<Interrupt> = RefCell::new(Interrupt::default());
+}
+
+#[derive(Clone, Copy)]
+enum Stop {
+    Steps,
+    Timeout,
+    Interrupted,
+}
+
+// Stops the evaluations of an interpreter from another thread. It is
+// cleared when each one starts and ends.
+#[derive(Clone, Debug, Default)]
+pub struct Interrupt(Arc<AtomicBool>);
+
+impl Interrupt {
+    // Stops the evaluation that is running, within a few steps
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed)
+    }
+
+    // Whether an evaluation has been interrupted and not ended yet
+    pub fn interrupted(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn clear(&self) {
+        self.0.store(false, Ordering::Relaxed)
+    }
+}
+
+// Steps between looks at the clock and the Interrupt
+const CHECK_EVERY: usize = 1024;
+
+const EXCEEDED: &str = "limit exceeded: ";
+
+// What an evaluation has used of its limits, while one nested in it
+// runs
+struct Saved {
+    owner: Option<usize>,
+    interrupt: Interrupt,
+    limits: Limits,
+    steps: usize,
+    deadline: Option<Instant>,
+    stopped: Option<Stop>,
+}
+
+// The limits of the evaluation running
+pub fn limits() -> Limits {
+    LIMITS.with(|l| l.get())
+}
+
+// Whether an evaluation by owner is running
+pub fn evaluating(owner: usize) -> bool {
+    OWNER.with(|o| o.get()) == Some(owner)
+}
+
+// Runs f as an evaluation by owner, an interpreter, with its limits
+// starting afresh unless it is part of one by owner already running.
+pub fn evaluation<T>(
+    owner: usize,
+    limits: Limits,
+    interrupt: &Interrupt,
+    f: impl FnOnce() -> T,
+) -> T {
+    if evaluating(owner) {
+        return f();
+    }
+    interrupt.clear();
+    let outer = Saved {
+        owner: OWNER.with(|o| o.replace(Some(owner))),
+        interrupt: INTERRUPT.with(|i| i.replace(interrupt.clone())),
+        limits: LIMITS.with(|l| l.replace(limits)),
+        steps: STEPS.with(|s| s.replace((0, next_check(0, limits))).0),
+        deadline: DEADLINE.with(|d| d.replace(limits.timeout.map(|t| Instant::now() + t))),
+        stopped: STOPPED.with(|s| s.replace(None)),
+    };
+    let res = f();
+    interrupt.clear();
+    OWNER.with(|o| o.set(outer.owner));
+    LIMITS.with(|l| l.set(outer.limits));
+    // the outer evaluation looks at its limits at its next step
+    STEPS.with(|s| s.set((outer.steps, outer.steps + 1)));
+    DEADLINE.with(|d| d.set(outer.deadline));
+    STOPPED.with(|s| s.set(outer.stopped));
+    INTERRUPT.with(|i| *i.borrow_mut() = outer.interrupt);
+    res
+}
+
+// Called by the evaluators for each step
+pub fn step() -> Result<(), MalErr> {
+    let (steps, check) = STEPS.with(|s| {
+        let (steps, check) = s.get();
+        s.set((steps + 1, check));
+        (steps + 1, check)
+    });
+    if steps < check {
+        return Ok(());
+    }
+    check_limits(steps)
+}
+
+fn next_check(steps: usize, limits: Limits) -> usize {
+    let next = steps + CHECK_EVERY;
+    match limits.max_steps {
+        Some(max) => next.min(max + 1),
+        None => next,
+    }
+}
+
+fn check_limits(steps: usize) -> Result<(), MalErr> {
+    let limits = limits();
+    let mut stopped = STOPPED.with(|s| s.get());
+    if stopped.is_none() && INTERRUPT.with(|i| i.borrow().interrupted()) {
+        stopped = Some(Stop::Interrupted);
+    }
+    if stopped.is_none() && limits.max_steps.is_some_and(|max| steps > max) {
+        stopped = Some(Stop::Steps);
+    }
+    if let Some(deadline) = DEADLINE.with(|d| d.get()) {
+        if stopped.is_none() && Instant::now() >= deadline {
+            stopped = Some(Stop::Timeout);
+        }
+    }
+    let stop = match stopped {
+        Some(stop) => stop,
+        None => {
+            STEPS.with(|s| s.set((steps, next_check(steps, limits))));
+            return Ok(());
+        }
+    };
+    STOPPED.with(|s| s.set(Some(stop)));
+    // so that the next step fails too
+    STEPS.with(|s| s.set((steps, steps + 1)));
+    Err(match stop {
+        Stop::Steps => exceeded(&format!(
+            "more than {} steps",
+            limits.max_steps.unwrap_or(0)
+        )),
+        Stop::Timeout => {
+            let timeout = limits.timeout.unwrap_or_default();
+            exceeded(&format!("took over {} ms", timeout.as_millis()))
+        }
+        Stop::Interrupted => ErrString("interrupted".to_string()),
+    })
+}
+
+// Passes on what a builtin made, unless it is over the size limit
+pub fn sized(res: MalRet) -> MalRet {
+    if limits().max_size.is_none() {
+        return res;
+    }
+    let v = res?;
+    check_size(match &v {
+        List(l, _) | Vector(l, _) => l.len(),
+        Hash(hm, _) => hm.len(),
+        SortedMap(t, _) | SortedSet(t, _) => t.len(),
+        Str(s) => s.len(),
+        _ => 0,
+    })?;
+    Ok(v)
+}
+
+// Fails if size is over the size limit, e.g. for what is about to be
+// read
+pub fn check_size(size: usize) -> Result<(), MalErr> {
+    match limits().max_size {
+        Some(max) if size > max => Err(exceeded(&format!("size {} is over {}", size, max))),
+        _ => Ok(()),
+    }
+}
+
+// Fails if depth is over the depth limit
+pub fn check_depth(depth: usize) -> Result<(), MalErr> {
+    match limits().max_depth {
+        Some(max) if depth > max => Err(exceeded(&format!("more than {} calls deep", max))),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn exceeded(what: &str) -> MalErr {
+    ErrString(format!("{}{}", EXCEEDED, what))
+}
+
+// Whether e is the error for going over a limit, rather than one from
+// the program
+pub fn is_exceeded(e: &MalErr) -> bool {
+    matches!(e, ErrString(s) if s.starts_with(EXCEEDED))
+}
@@ -1,5 +1,9 @@
-use crate::types::MalVal;
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
+use itertools::Itertools;
+
+use crate::types::MalVal::{
+    Atom, Bool, Func, Hash, Int, Kw, List, MalFunc, Nil, SortedMap, SortedSet, Str, Sym, Vector,
+};
+use crate::types::{key_val, MalVal};
 
 fn escape_str(s: &str) -> String {
     s.chars()
@@ -22,24 +26,35 @@ impl MalVal {
             Int(i) => format!("{}", i),
             //Float(f)    => format!("{}", f),
             Str(s) => {
-                if let Some(keyword) = s.strip_prefix('\u{29e}') {
-                    format!(":{}", keyword)
-                } else if print_readably {
+                if print_readably {
                     format!("\"{}\"", escape_str(s))
                 } else {
                     s.clone()
                 }
             }
-            Sym(s) => s.clone(),
+            Sym(s) => s.to_string(),
+            Kw(k) => format!(":{}", k),
             List(l, _) => pr_seq(l, print_readably, "(", ")", " "),
             Vector(l, _) => pr_seq(l, print_readably, "[", "]", " "),
             Hash(hm, _) => {
+                // Print entries in key order so that the same map always
+                // prints the same text.
                 let l: Vec<MalVal> = hm
                     .iter()
-                    .flat_map(|(k, v)| vec![Str(k.to_string()), v.clone()])
+                    .sorted_by(|(k1, _), (k2, _)| k1.cmp(k2))
+                    .flat_map(|(k, v)| vec![key_val(k), v.clone()])
+                    .collect();
+                pr_seq(&l, print_readably, "{", "}", " ")
+            }
+            SortedMap(t, _) => {
+                let l: Vec<MalVal> = t
+                    .entries()
+                    .into_iter()
+                    .flat_map(|(k, v)| vec![k, v])
                     .collect();
                 pr_seq(&l, print_readably, "{", "}", " ")
             }
+            SortedSet(t, _) => pr_seq(&t.keys(), print_readably, "#{", "}", " "),
             Func(_, _) => String::from("#<builtin>"),
             MalFunc {
                 ast: a, params: p, ..
@@ -0,0 +1,194 @@
+use std::cell::{Cell, RefCell};
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use fnv::FnvHashMap;
+
+use crate::symbol::{intern, Symbol};
+use crate::types::MalVal;
+use crate::types::MalVal::{Func, MalFunc};
+
+// A profiler for mal code. While it is on, every function call made
+// through MalVal::apply or by an evaluator directly is timed. Builtins
+// are named as they were created and mal functions by the symbol they
+// were first def!'d to. A tail call ends the call it replaces.
+//
+// Inclusive time is only counted for the outermost of several
+// recursive calls to the same function, so that it never exceeds the
+// time that was actually spent.
+
+thread_local! {
+    static PROFILING: Cell<bool> = const { Cell::new(false) };
+    static PROFILE: RefCell<Profile> = RefCell::new(Profile::default());
+}
+
+#[derive(Clone, Copy, Default)]
+struct Stats {
+    calls: u64,
+    total: Duration,
+    own: Duration,
+}
+
+struct Call {
+    name: Symbol,
+    // its stack in the tree of stacks
+    node: usize,
+    start: Instant,
+    // time spent in the calls it made
+    children: Duration,
+}
+
+#[derive(Default)]
+struct Profile {
+    stack: Vec<Call>,
+    stats: FnvHashMap<Symbol, Stats>,
+    // how many calls of each function are on the stack
+    active: FnvHashMap<Symbol, usize>,
+    // every stack seen, as (caller's node, name), with its own time
+    nodes: Vec<(Option<usize>, Symbol, Duration)>,
+    children: FnvHashMap<(Option<usize>, Symbol), usize>,
+}
+
+lazy_static! {
+    static ref ANONYMOUS: Symbol = intern("anonymous");
+}
+
+// Starts a new profile, or stops profiling and keeps what was
+// recorded for report and folded.
+pub fn set_profile(on: bool) {
+    if on {
+        PROFILE.with(|p| *p.borrow_mut() = Profile::default());
+    }
+    PROFILING.with(|p| p.set(on));
+}
+
+pub fn enabled() -> bool {
+    PROFILING.with(|p| p.get())
+}
+
+// Gives a mal function the name it is def!'d to, unless it already
+// has one.
+pub fn name_fn(f: &MalVal, name: Symbol) {
+    if let MalFunc {
+        code: Some(code), ..
+    } = f
+    {
+        if code.name().is_none() {
+            code.set_name(name)
+        }
+    }
+}
+
+fn name_of(f: &MalVal) -> Symbol {
+    match f {
+        Func(b, _) => intern(&b.name),
+        MalFunc {
+            code: Some(code), ..
+        } => code.name().unwrap_or(*ANONYMOUS),
+        _ => *ANONYMOUS,
+    }
+}
+
+pub fn enter(f: &MalVal) {
+    let name = name_of(f);
+    PROFILE.with(|p| {
+        let p = &mut *p.borrow_mut();
+        *p.active.entry(name).or_insert(0) += 1;
+        let parent = p.stack.last().map(|c| c.node);
+        let nodes = &mut p.nodes;
+        let node = *p.children.entry((parent, name)).or_insert_with(|| {
+            nodes.push((parent, name, Duration::ZERO));
+            nodes.len() - 1
+        });
+        p.stack.push(Call {
+            name,
+            node,
+            start: Instant::now(),
+            children: Duration::ZERO,
+        });
+    })
+}
+
+pub fn leave() {
+    PROFILE.with(|p| {
+        let p = &mut *p.borrow_mut();
+        let call = match p.stack.pop() {
+            Some(call) => call,
+            None => return,
+        };
+        let total = call.start.elapsed();
+        let own = total.saturating_sub(call.children);
+        p.nodes[call.node].2 += own;
+        if let Some(caller) = p.stack.last_mut() {
+            caller.children += total;
+        }
+        let active = p.active.entry(call.name).or_insert(1);
+        *active -= 1;
+        let outermost = *active == 0;
+        let stats = p.stats.entry(call.name).or_default();
+        stats.calls += 1;
+        stats.own += own;
+        if outermost {
+            stats.total += total;
+        }
+    })
+}
+
+// A tail call from the function being profiled to f
+pub fn replace(f: &MalVal) {
+    leave();
+    enter(f);
+}
+
+fn ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+// A table of the functions called, the most time spent in them first
+pub fn report() -> String {
+    PROFILE.with(|p| {
+        let p = p.borrow();
+        let mut rows: Vec<(Symbol, Stats)> = p.stats.iter().map(|(k, v)| (*k, *v)).collect();
+        rows.sort_by(|(a, sa), (b, sb)| sb.own.cmp(&sa.own).then(a.cmp(b)));
+        let mut out = format!(
+            "{:>10} {:>12} {:>12}  {}\n",
+            "calls", "total ms", "self ms", "function"
+        );
+        for (name, s) in rows {
+            let _ = writeln!(
+                out,
+                "{:>10} {:>12.3} {:>12.3}  {}",
+                s.calls,
+                ms(s.total),
+                ms(s.own),
+                name
+            );
+        }
+        out
+    })
+}
+
+// The stacks in the folded format of flamegraph.pl and inferno, one
+// line per stack with its own time in microseconds. Stacks that took
+// under a microsecond are left out, as they would not show.
+pub fn folded() -> String {
+    PROFILE.with(|p| {
+        let p = p.borrow();
+        let mut lines: Vec<String> = p
+            .nodes
+            .iter()
+            .filter(|&&(_, _, d)| d.as_micros() > 0)
+            .map(|&(mut parent, name, d)| {
+                let mut names = vec![name.name()];
+                while let Some(n) = parent {
+                    names.push(p.nodes[n].1.name());
+                    parent = p.nodes[n].0;
+                }
+                names.reverse();
+                format!("{} {}", names.join(";"), d.as_micros())
+            })
+            .collect();
+        lines.sort();
+        lines.iter().map(|l| format!("{}\n", l)).collect()
+    })
+}
@@ -1,18 +1,26 @@
 use regex::{Captures, Regex};
 use std::rc::Rc;
 
+use crate::symbol::{intern, Keyword};
 use crate::types::MalErr::ErrString;
-use crate::types::MalVal::{Bool, Int, List, Nil, Str, Sym, Vector};
-use crate::types::{error, hash_map, MalErr, MalRet, MalVal};
+use crate::types::MalVal::{Bool, Int, Kw, List, Nil, Str, Sym, Vector};
+use crate::types::{count_read, data_symbol, error, hash_map, symbol, MalErr, MalRet, MalVal};
 
-#[derive(Debug, Clone)]
-struct Reader {
-    tokens: Vec<String>,
-    pos: usize,
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Reader {
+    pub(crate) tokens: Vec<String>,
+    pub(crate) pos: usize,
+    // when reading with lines: the line each token starts on, and the
+    // lists read so far with the line each one starts on
+    pub(crate) lines: Vec<usize>,
+    pub(crate) lists: Vec<(MalVal, usize)>,
+    // when reading data rather than code, whose names are not to fill
+    // the symbol table
+    pub(crate) data: bool,
 }
 
 impl Reader {
-    fn next(&mut self) -> Result<String, MalErr> {
+    pub(crate) fn next(&mut self) -> Result<String, MalErr> {
         self.pos += 1;
         Ok(self
             .tokens
@@ -20,7 +28,7 @@ impl Reader {
             .ok_or_else(|| ErrString("underflow".to_string()))?
             .to_string())
     }
-    fn peek(&self) -> Result<String, MalErr> {
+    pub(crate) fn peek(&self) -> Result<String, MalErr> {
         Ok(self
             .tokens
             .get(self.pos)
@@ -29,16 +37,15 @@ impl Reader {
     }
 }
 
-fn tokenize(str: &str) -> Vec<String> {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(
-            r###"[\s,]*(~@|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"?|;.*|[^\s\[\]{}('"`,;)]+)"###
-        )
-        .unwrap();
-    }
+lazy_static! {
+    static ref TOKEN_RE: Regex =
+        Regex::new(r###"[\s,]*(~@|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"?|;.*|[^\s\[\]{}('"`,;)]+)"###)
+            .unwrap();
+}
 
+pub(crate) fn tokenize(str: &str) -> Vec<String> {
     let mut res = vec![];
-    for cap in RE.captures_iter(str) {
+    for cap in TOKEN_RE.captures_iter(str) {
         if cap[1].starts_with(';') {
             continue;
         }
@@ -47,6 +54,23 @@ fn tokenize(str: &str) -> Vec<String> {
     res
 }
 
+// As tokenize, with the line number of each token
+fn tokenize_lines(str: &str) -> (Vec<String>, Vec<usize>) {
+    let (mut res, mut lines) = (vec![], vec![]);
+    let (mut line, mut seen) = (1, 0);
+    for cap in TOKEN_RE.captures_iter(str) {
+        let tok = cap.get(1).unwrap();
+        line += str[seen..tok.start()].matches('\n').count();
+        seen = tok.start();
+        if tok.as_str().starts_with(';') {
+            continue;
+        }
+        res.push(tok.as_str().to_string());
+        lines.push(line);
+    }
+    (res, lines)
+}
+
 fn unescape_str(s: &str) -> String {
     lazy_static! {
         static ref RE: Regex = Regex::new(r#"\\(.)"#).unwrap();
@@ -57,7 +81,7 @@ fn unescape_str(s: &str) -> String {
     .to_string()
 }
 
-fn read_atom(rdr: &mut Reader) -> MalRet {
+pub(crate) fn read_atom(rdr: &mut Reader) -> MalRet {
     lazy_static! {
         static ref INT_RE: Regex = Regex::new(r"^-?[0-9]+$").unwrap();
         static ref STR_RE: Regex = Regex::new(r#""(?:\\.|[^\\"])*""#).unwrap();
@@ -74,10 +98,16 @@ fn read_atom(rdr: &mut Reader) -> MalRet {
                 Ok(Str(unescape_str(&token[1..token.len() - 1])))
             } else if token.starts_with('\"') {
                 error("expected '\"', got EOF")
-            } else if let Some(keyword) = token.strip_prefix(':') {
-                Ok(Str(format!("\u{29e}{}", keyword)))
+            } else if let Some(name) = token.strip_prefix(':') {
+                Ok(Kw(if rdr.data {
+                    Keyword::from_data(name)
+                } else {
+                    Keyword::new(name)
+                }))
+            } else if rdr.data {
+                data_symbol(&token)
             } else {
-                Ok(Sym(token.to_string()))
+                symbol(&token)
             }
         }
     }
@@ -85,6 +115,7 @@ fn read_atom(rdr: &mut Reader) -> MalRet {
 
 fn read_seq(rdr: &mut Reader, end: &str) -> MalRet {
     let mut seq: Vec<MalVal> = vec![];
+    let line = rdr.lines.get(rdr.pos).copied();
     rdr.next()?;
     loop {
         let token = match rdr.peek() {
@@ -98,7 +129,13 @@ fn read_seq(rdr: &mut Reader, end: &str) -> MalRet {
     }
     let _ = rdr.next();
     match end {
-        ")" => Ok(list!(seq)),
+        ")" => {
+            let l = list!(seq);
+            if let Some(line) = line {
+                rdr.lists.push((l.clone(), line));
+            }
+            Ok(l)
+        }
         "]" => Ok(vector!(seq)),
         "}" => hash_map(seq),
         _ => error("read_seq unknown end value"),
@@ -110,28 +147,28 @@ fn read_form(rdr: &mut Reader) -> MalRet {
     match &token[..] {
         "'" => {
             let _ = rdr.next();
-            Ok(list![Sym("quote".to_string()), read_form(rdr)?])
+            Ok(list![Sym(intern("quote")), read_form(rdr)?])
         }
         "`" => {
             let _ = rdr.next();
-            Ok(list![Sym("quasiquote".to_string()), read_form(rdr)?])
+            Ok(list![Sym(intern("quasiquote")), read_form(rdr)?])
         }
         "~" => {
             let _ = rdr.next();
-            Ok(list![Sym("unquote".to_string()), read_form(rdr)?])
+            Ok(list![Sym(intern("unquote")), read_form(rdr)?])
         }
         "~@" => {
             let _ = rdr.next();
-            Ok(list![Sym("splice-unquote".to_string()), read_form(rdr)?])
+            Ok(list![Sym(intern("splice-unquote")), read_form(rdr)?])
         }
         "^" => {
             let _ = rdr.next();
             let meta = read_form(rdr)?;
-            Ok(list![Sym("with-meta".to_string()), read_form(rdr)?, meta])
+            Ok(list![Sym(intern("with-meta")), read_form(rdr)?, meta])
         }
         "@" => {
             let _ = rdr.next();
-            Ok(list![Sym("deref".to_string()), read_form(rdr)?])
+            Ok(list![Sym(intern("deref")), read_form(rdr)?])
         }
         ")" => error("unexpected ')'"),
         "(" => read_seq(rdr, ")"),
@@ -144,6 +181,7 @@ fn read_form(rdr: &mut Reader) -> MalRet {
 }
 
 pub fn read_str(str: &str) -> MalRet {
+    count_read();
     let tokens = tokenize(str);
     //println!("tokens: {:?}", tokens);
     if tokens.is_empty() {
@@ -151,6 +189,26 @@ pub fn read_str(str: &str) -> MalRet {
     }
     read_form(&mut Reader {
         pos: 0,
-        tokens
+        tokens,
+        ..Default::default()
     })
 }
+
+// Reads str as read_str does, also returning each list read with the
+// line it starts on.
+pub fn read_str_lines(str: &str) -> Result<(MalVal, Vec<(MalVal, usize)>), MalErr> {
+    count_read();
+    let (tokens, lines) = tokenize_lines(str);
+    if tokens.is_empty() {
+        return Err(ErrString("no input".to_string()));
+    }
+    let mut rdr = Reader {
+        pos: 0,
+        tokens,
+        lines,
+        lists: vec![],
+        data: false,
+    };
+    let form = read_form(&mut rdr)?;
+    Ok((form, rdr.lists))
+}
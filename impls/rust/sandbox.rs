@@ -0,0 +1,172 @@
+use std::cell::RefCell;
+use std::fs;
+use std::mem;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::core;
+use crate::limits::{check_size, exceeded};
+use crate::printer::pr_seq;
+use crate::types::Arity::{AtLeast, Exactly};
+use crate::types::MalErr::ErrString;
+use crate::types::MalVal::{Nil, Str};
+use crate::types::{builtin, error, MalArgs, MalErr, MalRet, MalVal};
+
+// What the programs an Interpreter runs may do outside of it, for
+// running ones that are not trusted. A sandbox has its own versions of
+// the builtins in IO, which can only read files under the directories
+// it allows, and whose output, like that of DEBUG-EVAL, can be kept
+// from the terminal. It leaves out set-trace! and break, which use the
+// terminal. The default allows nothing, and captures up to 1 MiB of
+// output.
+
+#[derive(Clone, Debug)]
+pub struct Sandbox {
+    // directories whose files slurp and load-file may read
+    pub read_dirs: Vec<PathBuf>,
+    // whether readline is there to read from the terminal
+    pub readline: bool,
+    pub output: Output,
+    // bytes of output captured and not taken yet, past which printing
+    // fails with a "limit exceeded" error
+    pub max_output: usize,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox {
+            read_dirs: vec![],
+            readline: false,
+            output: Output::default(),
+            max_output: 1 << 20,
+        }
+    }
+}
+
+// Where prn and println write to
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Output {
+    Stdout,
+    // to be taken with Interpreter::take_output
+    #[default]
+    Capture,
+    Discard,
+}
+
+// The builtins of core::ns() that a sandbox replaces or leaves out
+pub const IO: &[&str] = &["slurp", "readline", "prn", "println"];
+
+// Where the output of an interpreter goes
+pub struct Sink {
+    output: Output,
+    max_output: usize,
+    captured: RefCell<String>,
+}
+
+thread_local! {
+    // that of the evaluation running, if it is sandboxed
+    static SINK: RefCell<Option<Rc<Sink>>> = const { RefCell::new(None) };
+}
+
+impl Sink {
+    pub fn new(sandbox: &Sandbox) -> Sink {
+        Sink {
+            output: sandbox.output,
+            max_output: sandbox.max_output,
+            captured: RefCell::new(String::new()),
+        }
+    }
+
+    pub fn print_line(&self, line: &str) -> Result<(), MalErr> {
+        match self.output {
+            Output::Stdout => println!("{}", line),
+            Output::Capture => {
+                let mut captured = self.captured.borrow_mut();
+                if captured.len() + line.len() + 1 > self.max_output {
+                    return Err(exceeded(&format!("output over {} bytes", self.max_output)));
+                }
+                captured.push_str(line);
+                captured.push('\n');
+            }
+            Output::Discard => (),
+        }
+        Ok(())
+    }
+
+    // What has been captured since the last call
+    pub fn take(&self) -> String {
+        mem::take(&mut *self.captured.borrow_mut())
+    }
+}
+
+// Runs f with the output of the evaluation going to sink, or stdout
+pub fn with_sink<T>(sink: Option<&Rc<Sink>>, f: impl FnOnce() -> T) -> T {
+    let outer = SINK.with(|s| s.replace(sink.cloned()));
+    let res = f();
+    SINK.with(|s| *s.borrow_mut() = outer);
+    res
+}
+
+// Prints line where the output of the evaluation running goes. Output
+// that does not fit is dropped.
+pub fn print_line(line: &str) {
+    SINK.with(|s| match &*s.borrow() {
+        Some(sink) => {
+            let _ = sink.print_line(line);
+        }
+        None => println!("{}", line),
+    })
+}
+
+impl Sandbox {
+    // The contents of the file at path, if it is in one of read_dirs
+    pub fn read(&self, path: &str) -> Result<String, MalErr> {
+        let full = fs::canonicalize(path).map_err(|e| ErrString(e.to_string()))?;
+        let allowed = self
+            .read_dirs
+            .iter()
+            .filter_map(|d| fs::canonicalize(d).ok())
+            .any(|d| full.starts_with(d));
+        if !allowed {
+            return Err(ErrString(format!("{}: not readable in the sandbox", path)));
+        }
+        if let Ok(meta) = fs::metadata(&full) {
+            check_size(meta.len() as usize)?;
+        }
+        fs::read_to_string(full).map_err(|e| ErrString(e.to_string()))
+    }
+}
+
+// The sandbox's builtins for the names in IO, which print to sink
+pub fn ns(sandbox: &Rc<Sandbox>, sink: &Rc<Sink>) -> Vec<(&'static str, MalVal)> {
+    let sb = sandbox.clone();
+    let mut ns = vec![
+        (
+            "slurp",
+            builtin("slurp", Exactly(1), move |a| match &a[0] {
+                Str(path) => sb.read(path).map(Str),
+                _ => error("expecting (str) arg"),
+            }),
+        ),
+        ("prn", builtin("prn", AtLeast(0), print(sink, true))),
+        (
+            "println",
+            builtin("println", AtLeast(0), print(sink, false)),
+        ),
+    ];
+    if sandbox.readline {
+        ns.push((
+            "readline",
+            builtin("readline", Exactly(1), core::readline()),
+        ));
+    }
+    ns
+}
+
+fn print(sink: &Rc<Sink>, readably: bool) -> impl Fn(MalArgs) -> MalRet {
+    let sink = sink.clone();
+    move |a| {
+        sink.print_line(&pr_seq(&a, readably, "", "", " "))?;
+        Ok(Nil)
+    }
+}
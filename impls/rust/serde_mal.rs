@@ -0,0 +1,598 @@
+use std::fmt;
+use std::rc::Rc;
+
+use fnv::FnvHashMap;
+use itertools::Itertools;
+use serde::de::value::{MapDeserializer, SeqDeserializer};
+use serde::de::{
+    self, Deserialize, DeserializeOwned, Deserializer, EnumAccess, IntoDeserializer, MapAccess,
+    SeqAccess, VariantAccess, Visitor,
+};
+use serde::ser::{self, Serialize, SerializeMap, SerializeSeq, Serializer};
+
+use crate::types::MalErr::ErrString;
+use crate::types::MalVal::{Bool, Hash, Int, Kw, List, Nil, SortedMap, SortedSet, Str, Vector};
+use crate::types::{hash_key, key_val, keyword, new_hash, MalErr, MalRet, MalVal};
+
+// serde support for data-only values: nil, booleans, integers,
+// strings, keywords, lists, vectors and maps. Keywords are written as
+// their bare name, which is also how map keys come back out, so Rust
+// struct fields map onto keyword keys and unit enum variants onto
+// keywords. Functions and atoms cannot be serialized.
+//
+// serde has no keywords, lists or sorted collections, so a value that
+// goes through another format, e.g. JSON, does not always come back
+// the same: keywords come back as strings, lists as vectors, sorted
+// maps as hash-maps and sorted sets as vectors. Formats whose map keys
+// are strings write keys other than strings and keywords as they see
+// fit; serde_json writes integers as strings and refuses the rest.
+
+impl ser::Error for MalErr {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        ErrString(msg.to_string())
+    }
+}
+
+impl de::Error for MalErr {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        ErrString(msg.to_string())
+    }
+}
+
+fn bare_name(s: &str) -> &str {
+    s.strip_prefix('\u{29e}').unwrap_or(s)
+}
+
+fn keyword_key(s: &str) -> String {
+    format!("\u{29e}{}", s)
+}
+
+// Functions and atoms cannot be hash-map keys
+fn map_key(k: &MalVal) -> Result<String, MalErr> {
+    hash_key(k).ok_or_else(|| ErrString(format!("{} cannot be a map key", k.pr_str(true))))
+}
+
+// The key a hash-map entry is written with: strings and keywords by
+// name, other keys as they are
+fn entry_key(k: &str) -> MalVal {
+    match key_val(k) {
+        Kw(name) => Str(name.to_string()),
+        v => v,
+    }
+}
+
+// Rust value -> MalVal
+
+pub fn to_value<T: Serialize + ?Sized>(v: &T) -> MalRet {
+    v.serialize(ValueSerializer)
+}
+
+impl Serialize for MalVal {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Nil => s.serialize_unit(),
+            Bool(b) => s.serialize_bool(*b),
+            Int(i) => s.serialize_i64(*i),
+            Str(st) => s.serialize_str(st),
+            Kw(k) => s.serialize_str(k),
+            List(l, _) | Vector(l, _) => s.collect_seq(l.iter()),
+            // sorted by name, so output is stable, and a keyword and
+            // string of the same name would be written as the same key
+            Hash(hm, _) => {
+                let entries: Vec<_> = hm
+                    .iter()
+                    .sorted_by_key(|(k, _)| (bare_name(k), k.as_str()))
+                    .collect();
+                for pair in entries.windows(2) {
+                    if bare_name(pair[0].0) == bare_name(pair[1].0) {
+                        return Err(ser::Error::custom(format!(
+                            "keys {} and {} are the same",
+                            key_val(pair[0].0).pr_str(true),
+                            key_val(pair[1].0).pr_str(true)
+                        )));
+                    }
+                }
+                let mut m = s.serialize_map(Some(hm.len()))?;
+                for (k, v) in entries {
+                    m.serialize_entry(&entry_key(k), v)?;
+                }
+                m.end()
+            }
+            SortedMap(t, _) => {
+                let mut m = s.serialize_map(Some(t.len()))?;
+                for (k, v) in t.entries() {
+                    m.serialize_entry(&k, &v)?;
+                }
+                m.end()
+            }
+            SortedSet(t, _) => s.collect_seq(t.keys()),
+            _ => Err(ser::Error::custom(format!(
+                "cannot serialize {}",
+                self.pr_str(true)
+            ))),
+        }
+    }
+}
+
+struct ValueSerializer;
+
+struct SeqBuilder {
+    items: Vec<MalVal>,
+    // set for tuple variants, which become {:Variant [...]}
+    variant: Option<&'static str>,
+}
+
+struct MapBuilder {
+    hm: FnvHashMap<String, MalVal>,
+    next_key: Option<String>,
+    // set for struct variants, which become {:Variant {...}}
+    variant: Option<&'static str>,
+}
+
+fn wrap_variant(variant: Option<&'static str>, v: MalVal) -> MalVal {
+    match variant {
+        Some(name) => {
+            let mut hm = FnvHashMap::default();
+            hm.insert(keyword_key(name), v);
+            new_hash(hm)
+        }
+        None => v,
+    }
+}
+
+impl Serializer for ValueSerializer {
+    type Ok = MalVal;
+    type Error = MalErr;
+    type SerializeSeq = SeqBuilder;
+    type SerializeTuple = SeqBuilder;
+    type SerializeTupleStruct = SeqBuilder;
+    type SerializeTupleVariant = SeqBuilder;
+    type SerializeMap = MapBuilder;
+    type SerializeStruct = MapBuilder;
+    type SerializeStructVariant = MapBuilder;
+
+    fn serialize_bool(self, v: bool) -> MalRet {
+        Ok(Bool(v))
+    }
+    fn serialize_i8(self, v: i8) -> MalRet {
+        Ok(Int(v as i64))
+    }
+    fn serialize_i16(self, v: i16) -> MalRet {
+        Ok(Int(v as i64))
+    }
+    fn serialize_i32(self, v: i32) -> MalRet {
+        Ok(Int(v as i64))
+    }
+    fn serialize_i64(self, v: i64) -> MalRet {
+        Ok(Int(v))
+    }
+    fn serialize_u8(self, v: u8) -> MalRet {
+        Ok(Int(v as i64))
+    }
+    fn serialize_u16(self, v: u16) -> MalRet {
+        Ok(Int(v as i64))
+    }
+    fn serialize_u32(self, v: u32) -> MalRet {
+        Ok(Int(v as i64))
+    }
+    fn serialize_u64(self, v: u64) -> MalRet {
+        if v > i64::MAX as u64 {
+            return Err(ErrString(format!("integer {} out of range", v)));
+        }
+        Ok(Int(v as i64))
+    }
+    // mal only has integers
+    fn serialize_f32(self, v: f32) -> MalRet {
+        self.serialize_f64(v as f64)
+    }
+    fn serialize_f64(self, v: f64) -> MalRet {
+        if v.fract() == 0.0 && v >= i64::MIN as f64 && v < i64::MAX as f64 {
+            Ok(Int(v as i64))
+        } else {
+            Err(ErrString(format!("cannot represent {} as an integer", v)))
+        }
+    }
+    fn serialize_char(self, v: char) -> MalRet {
+        Ok(Str(v.to_string()))
+    }
+    fn serialize_str(self, v: &str) -> MalRet {
+        Ok(Str(v.to_string()))
+    }
+    fn serialize_bytes(self, v: &[u8]) -> MalRet {
+        Ok(vector!(v.iter().map(|b| Int(*b as i64)).collect()))
+    }
+    fn serialize_none(self) -> MalRet {
+        Ok(Nil)
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, v: &T) -> MalRet {
+        v.serialize(self)
+    }
+    fn serialize_unit(self) -> MalRet {
+        Ok(Nil)
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> MalRet {
+        Ok(Nil)
+    }
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _idx: u32,
+        variant: &'static str,
+    ) -> MalRet {
+        Ok(keyword(variant))
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, v: &T) -> MalRet {
+        v.serialize(self)
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _idx: u32,
+        variant: &'static str,
+        v: &T,
+    ) -> MalRet {
+        Ok(wrap_variant(Some(variant), v.serialize(self)?))
+    }
+    fn serialize_seq(self, len: Option<usize>) -> Result<SeqBuilder, MalErr> {
+        Ok(SeqBuilder {
+            items: Vec::with_capacity(len.unwrap_or(0)),
+            variant: None,
+        })
+    }
+    fn serialize_tuple(self, len: usize) -> Result<SeqBuilder, MalErr> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<SeqBuilder, MalErr> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _idx: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SeqBuilder, MalErr> {
+        Ok(SeqBuilder {
+            items: Vec::with_capacity(len),
+            variant: Some(variant),
+        })
+    }
+    fn serialize_map(self, _len: Option<usize>) -> Result<MapBuilder, MalErr> {
+        Ok(MapBuilder {
+            hm: FnvHashMap::default(),
+            next_key: None,
+            variant: None,
+        })
+    }
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<MapBuilder, MalErr> {
+        self.serialize_map(Some(len))
+    }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _idx: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<MapBuilder, MalErr> {
+        Ok(MapBuilder {
+            hm: FnvHashMap::default(),
+            next_key: None,
+            variant: Some(variant),
+        })
+    }
+}
+
+impl SerializeSeq for SeqBuilder {
+    type Ok = MalVal;
+    type Error = MalErr;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), MalErr> {
+        self.items.push(to_value(v)?);
+        Ok(())
+    }
+    fn end(self) -> MalRet {
+        Ok(wrap_variant(self.variant, vector!(self.items)))
+    }
+}
+
+impl ser::SerializeTuple for SeqBuilder {
+    type Ok = MalVal;
+    type Error = MalErr;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), MalErr> {
+        SerializeSeq::serialize_element(self, v)
+    }
+    fn end(self) -> MalRet {
+        SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SeqBuilder {
+    type Ok = MalVal;
+    type Error = MalErr;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), MalErr> {
+        SerializeSeq::serialize_element(self, v)
+    }
+    fn end(self) -> MalRet {
+        SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleVariant for SeqBuilder {
+    type Ok = MalVal;
+    type Error = MalErr;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), MalErr> {
+        SerializeSeq::serialize_element(self, v)
+    }
+    fn end(self) -> MalRet {
+        SerializeSeq::end(self)
+    }
+}
+
+impl SerializeMap for MapBuilder {
+    type Ok = MalVal;
+    type Error = MalErr;
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, k: &T) -> Result<(), MalErr> {
+        self.next_key = Some(map_key(&to_value(k)?)?);
+        Ok(())
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), MalErr> {
+        let k = self
+            .next_key
+            .take()
+            .expect("serialize_value before serialize_key");
+        self.hm.insert(k, to_value(v)?);
+        Ok(())
+    }
+    fn end(self) -> MalRet {
+        Ok(wrap_variant(self.variant, new_hash(self.hm)))
+    }
+}
+
+impl ser::SerializeStruct for MapBuilder {
+    type Ok = MalVal;
+    type Error = MalErr;
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        v: &T,
+    ) -> Result<(), MalErr> {
+        self.hm.insert(keyword_key(key), to_value(v)?);
+        Ok(())
+    }
+    fn end(self) -> MalRet {
+        SerializeMap::end(self)
+    }
+}
+
+impl ser::SerializeStructVariant for MapBuilder {
+    type Ok = MalVal;
+    type Error = MalErr;
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        v: &T,
+    ) -> Result<(), MalErr> {
+        ser::SerializeStruct::serialize_field(self, key, v)
+    }
+    fn end(self) -> MalRet {
+        SerializeMap::end(self)
+    }
+}
+
+// MalVal -> Rust value
+
+pub fn from_value<T: DeserializeOwned>(v: &MalVal) -> Result<T, MalErr> {
+    T::deserialize(v.clone())
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = MalVal;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a mal data value")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<MalVal, E> {
+        Ok(Bool(v))
+    }
+    fn visit_i64<E>(self, v: i64) -> Result<MalVal, E> {
+        Ok(Int(v))
+    }
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<MalVal, E> {
+        ValueSerializer.serialize_u64(v).map_err(|e| E::custom(e))
+    }
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<MalVal, E> {
+        ValueSerializer.serialize_f64(v).map_err(|e| E::custom(e))
+    }
+    fn visit_str<E>(self, v: &str) -> Result<MalVal, E> {
+        Ok(Str(v.to_string()))
+    }
+    fn visit_string<E>(self, v: String) -> Result<MalVal, E> {
+        Ok(Str(v))
+    }
+    fn visit_unit<E>(self) -> Result<MalVal, E> {
+        Ok(Nil)
+    }
+    fn visit_none<E>(self) -> Result<MalVal, E> {
+        Ok(Nil)
+    }
+    fn visit_some<D: Deserializer<'de>>(self, d: D) -> Result<MalVal, D::Error> {
+        MalVal::deserialize(d)
+    }
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<MalVal, A::Error> {
+        let mut v = vec![];
+        while let Some(item) = seq.next_element()? {
+            v.push(item);
+        }
+        Ok(vector!(v))
+    }
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<MalVal, A::Error> {
+        let mut hm = FnvHashMap::default();
+        while let Some((k, v)) = map.next_entry::<MalVal, MalVal>()? {
+            hm.insert(map_key(&k).map_err(de::Error::custom)?, v);
+        }
+        Ok(new_hash(hm))
+    }
+}
+
+impl<'de> Deserialize<'de> for MalVal {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<MalVal, D::Error> {
+        d.deserialize_any(ValueVisitor)
+    }
+}
+
+impl<'de> IntoDeserializer<'de, MalErr> for MalVal {
+    type Deserializer = MalVal;
+    fn into_deserializer(self) -> MalVal {
+        self
+    }
+}
+
+fn map_entries(v: &MalVal) -> Option<Vec<(MalVal, MalVal)>> {
+    match v {
+        Hash(hm, _) => Some(hm.iter().map(|(k, v)| (entry_key(k), v.clone())).collect()),
+        SortedMap(t, _) => Some(t.entries()),
+        _ => None,
+    }
+}
+
+impl<'de> Deserializer<'de> for MalVal {
+    type Error = MalErr;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MalErr> {
+        if let Some(entries) = map_entries(&self) {
+            let mut map = MapDeserializer::new(entries.into_iter());
+            let v = visitor.visit_map(&mut map)?;
+            map.end()?;
+            return Ok(v);
+        }
+        match self {
+            Nil => visitor.visit_unit(),
+            Bool(b) => visitor.visit_bool(b),
+            Int(i) => visitor.visit_i64(i),
+            Str(s) => visitor.visit_string(s),
+            Kw(k) => visitor.visit_str(&k),
+            List(l, _) | Vector(l, _) => {
+                let mut seq = SeqDeserializer::new(l.iter().cloned());
+                let v = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(v)
+            }
+            SortedSet(t, _) => {
+                let mut seq = SeqDeserializer::new(t.keys().into_iter());
+                let v = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(v)
+            }
+            _ => Err(ErrString(format!(
+                "cannot deserialize {}",
+                self.pr_str(true)
+            ))),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MalErr> {
+        match self {
+            Nil => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, MalErr> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    // A unit variant is a keyword or string naming it; any other
+    // variant is a single-entry map from its name to its contents.
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, MalErr> {
+        match (&self, map_entries(&self)) {
+            (Str(_), _) | (Kw(_), _) => visitor.visit_enum(Variant {
+                name: bare_name(&map_key(&self)?).to_string(),
+                value: None,
+            }),
+            (_, Some(ref entries)) if entries.len() == 1 => match entries[0].clone() {
+                (k @ Str(_), v) | (k @ Kw(_), v) => visitor.visit_enum(Variant {
+                    name: bare_name(&map_key(&k)?).to_string(),
+                    value: Some(v),
+                }),
+                (k, _) => Err(ErrString(format!(
+                    "expected a variant name, got {}",
+                    k.pr_str(true)
+                ))),
+            },
+            _ => Err(ErrString(format!(
+                "expected keyword or single-entry map for enum, got {}",
+                self.pr_str(true)
+            ))),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+struct Variant {
+    name: String,
+    value: Option<MalVal>,
+}
+
+impl<'de> EnumAccess<'de> for Variant {
+    type Error = MalErr;
+    type Variant = VariantValue;
+
+    fn variant_seed<S: de::DeserializeSeed<'de>>(
+        self,
+        seed: S,
+    ) -> Result<(S::Value, VariantValue), MalErr> {
+        let name = seed.deserialize(Str(self.name))?;
+        Ok((name, VariantValue(self.value)))
+    }
+}
+
+struct VariantValue(Option<MalVal>);
+
+impl<'de> VariantAccess<'de> for VariantValue {
+    type Error = MalErr;
+
+    fn unit_variant(self) -> Result<(), MalErr> {
+        match self.0 {
+            None | Some(Nil) => Ok(()),
+            Some(v) => Err(ErrString(format!(
+                "expected unit variant, got {}",
+                v.pr_str(true)
+            ))),
+        }
+    }
+
+    fn newtype_variant_seed<S: de::DeserializeSeed<'de>>(
+        self,
+        seed: S,
+    ) -> Result<S::Value, MalErr> {
+        seed.deserialize(self.0.unwrap_or(Nil))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, MalErr> {
+        self.0.unwrap_or(Nil).deserialize_seq(visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, MalErr> {
+        self.0.unwrap_or(Nil).deserialize_map(visitor)
+    }
+}
@@ -0,0 +1,302 @@
+use std::cmp::{max, Ordering};
+use std::rc::Rc;
+
+use crate::types::{compare_with, MalErr, MalVal};
+
+// Persistent AVL tree backing sorted-map and sorted-set. Updates copy
+// the path from the root to the changed node and share everything
+// else, so old versions of a collection stay valid.
+
+type Link = Option<Rc<Node>>;
+
+struct Node {
+    key: MalVal,
+    val: MalVal,
+    height: usize,
+    left: Link,
+    right: Link,
+}
+
+#[derive(Clone)]
+pub struct SortedTree {
+    root: Link,
+    len: usize,
+    // None means the default `compare` ordering
+    pub comparator: Option<MalVal>,
+}
+
+fn height(link: &Link) -> usize {
+    link.as_ref().map_or(0, |n| n.height)
+}
+
+fn node(key: MalVal, val: MalVal, left: Link, right: Link) -> Rc<Node> {
+    Rc::new(Node {
+        key,
+        val,
+        height: 1 + max(height(&left), height(&right)),
+        left,
+        right,
+    })
+}
+
+fn balance(key: MalVal, val: MalVal, left: Link, right: Link) -> Rc<Node> {
+    let (hl, hr) = (height(&left), height(&right));
+    if hl > hr + 1 {
+        let l = left.unwrap();
+        if height(&l.left) >= height(&l.right) {
+            node(
+                l.key.clone(),
+                l.val.clone(),
+                l.left.clone(),
+                Some(node(key, val, l.right.clone(), right)),
+            )
+        } else {
+            let lr = l.right.as_ref().unwrap();
+            node(
+                lr.key.clone(),
+                lr.val.clone(),
+                Some(node(
+                    l.key.clone(),
+                    l.val.clone(),
+                    l.left.clone(),
+                    lr.left.clone(),
+                )),
+                Some(node(key, val, lr.right.clone(), right)),
+            )
+        }
+    } else if hr > hl + 1 {
+        let r = right.unwrap();
+        if height(&r.right) >= height(&r.left) {
+            node(
+                r.key.clone(),
+                r.val.clone(),
+                Some(node(key, val, left, r.left.clone())),
+                r.right.clone(),
+            )
+        } else {
+            let rl = r.left.as_ref().unwrap();
+            node(
+                rl.key.clone(),
+                rl.val.clone(),
+                Some(node(key, val, left, rl.left.clone())),
+                Some(node(
+                    r.key.clone(),
+                    r.val.clone(),
+                    rl.right.clone(),
+                    r.right.clone(),
+                )),
+            )
+        }
+    } else {
+        node(key, val, left, right)
+    }
+}
+
+fn remove_min(n: &Rc<Node>) -> (MalVal, MalVal, Link) {
+    match &n.left {
+        None => (n.key.clone(), n.val.clone(), n.right.clone()),
+        Some(l) => {
+            let (k, v, rest) = remove_min(l);
+            let right = n.right.clone();
+            (
+                k,
+                v,
+                Some(balance(n.key.clone(), n.val.clone(), rest, right)),
+            )
+        }
+    }
+}
+
+fn collect(link: &Link, out: &mut Vec<(MalVal, MalVal)>) {
+    if let Some(n) = link {
+        collect(&n.left, out);
+        out.push((n.key.clone(), n.val.clone()));
+        collect(&n.right, out);
+    }
+}
+
+impl SortedTree {
+    pub fn new(comparator: Option<MalVal>) -> SortedTree {
+        SortedTree {
+            root: None,
+            len: 0,
+            comparator,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn cmp(&self, a: &MalVal, b: &MalVal) -> Result<Ordering, MalErr> {
+        compare_with(self.comparator.as_ref(), a, b)
+    }
+
+    pub fn get(&self, key: &MalVal) -> Result<Option<(MalVal, MalVal)>, MalErr> {
+        let mut link = &self.root;
+        while let Some(n) = link {
+            link = match self.cmp(key, &n.key)? {
+                Ordering::Less => &n.left,
+                Ordering::Greater => &n.right,
+                Ordering::Equal => return Ok(Some((n.key.clone(), n.val.clone()))),
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn insert(&self, key: MalVal, val: MalVal) -> Result<SortedTree, MalErr> {
+        let mut added = false;
+        let root = self._insert(&self.root, key, val, &mut added)?;
+        Ok(SortedTree {
+            root: Some(root),
+            len: self.len + added as usize,
+            comparator: self.comparator.clone(),
+        })
+    }
+
+    fn _insert(
+        &self,
+        link: &Link,
+        key: MalVal,
+        val: MalVal,
+        added: &mut bool,
+    ) -> Result<Rc<Node>, MalErr> {
+        let n = match link {
+            None => {
+                *added = true;
+                return Ok(node(key, val, None, None));
+            }
+            Some(n) => n,
+        };
+        match self.cmp(&key, &n.key)? {
+            Ordering::Less => {
+                let left = self._insert(&n.left, key, val, added)?;
+                Ok(balance(
+                    n.key.clone(),
+                    n.val.clone(),
+                    Some(left),
+                    n.right.clone(),
+                ))
+            }
+            Ordering::Greater => {
+                let right = self._insert(&n.right, key, val, added)?;
+                Ok(balance(
+                    n.key.clone(),
+                    n.val.clone(),
+                    n.left.clone(),
+                    Some(right),
+                ))
+            }
+            // keep the original key, like Clojure does
+            Ordering::Equal => Ok(node(n.key.clone(), val, n.left.clone(), n.right.clone())),
+        }
+    }
+
+    pub fn remove(&self, key: &MalVal) -> Result<SortedTree, MalErr> {
+        let mut removed = false;
+        let root = self._remove(&self.root, key, &mut removed)?;
+        Ok(SortedTree {
+            root,
+            len: self.len - removed as usize,
+            comparator: self.comparator.clone(),
+        })
+    }
+
+    fn _remove(&self, link: &Link, key: &MalVal, removed: &mut bool) -> Result<Link, MalErr> {
+        let n = match link {
+            None => return Ok(None),
+            Some(n) => n,
+        };
+        match self.cmp(key, &n.key)? {
+            Ordering::Less => {
+                let left = self._remove(&n.left, key, removed)?;
+                Ok(Some(balance(
+                    n.key.clone(),
+                    n.val.clone(),
+                    left,
+                    n.right.clone(),
+                )))
+            }
+            Ordering::Greater => {
+                let right = self._remove(&n.right, key, removed)?;
+                Ok(Some(balance(
+                    n.key.clone(),
+                    n.val.clone(),
+                    n.left.clone(),
+                    right,
+                )))
+            }
+            Ordering::Equal => {
+                *removed = true;
+                Ok(match (&n.left, &n.right) {
+                    (None, right) => right.clone(),
+                    (left, None) => left.clone(),
+                    (left, Some(right)) => {
+                        let (k, v, rest) = remove_min(right);
+                        Some(balance(k, v, left.clone(), rest))
+                    }
+                })
+            }
+        }
+    }
+
+    pub fn entries(&self) -> Vec<(MalVal, MalVal)> {
+        let mut out = Vec::with_capacity(self.len);
+        collect(&self.root, &mut out);
+        out
+    }
+
+    pub fn keys(&self) -> Vec<MalVal> {
+        self.entries().into_iter().map(|(k, _)| k).collect()
+    }
+
+    // Entries between the optional lower and upper bounds, in order. A
+    // bound is a key and whether the key itself is included. Subtrees
+    // that lie entirely outside the bounds are never visited.
+    pub fn range(
+        &self,
+        lower: Option<(&MalVal, bool)>,
+        upper: Option<(&MalVal, bool)>,
+    ) -> Result<Vec<(MalVal, MalVal)>, MalErr> {
+        let mut out = vec![];
+        self._range(&self.root, lower, upper, &mut out)?;
+        Ok(out)
+    }
+
+    fn _range(
+        &self,
+        link: &Link,
+        lower: Option<(&MalVal, bool)>,
+        upper: Option<(&MalVal, bool)>,
+        out: &mut Vec<(MalVal, MalVal)>,
+    ) -> Result<(), MalErr> {
+        let n = match link {
+            None => return Ok(()),
+            Some(n) => n,
+        };
+        let lo = match lower {
+            Some((k, _)) => self.cmp(&n.key, k)?,
+            None => Ordering::Greater,
+        };
+        let hi = match upper {
+            Some((k, _)) => self.cmp(&n.key, k)?,
+            None => Ordering::Less,
+        };
+        if lo == Ordering::Greater {
+            self._range(&n.left, lower, upper, out)?;
+        }
+        let above = lo == Ordering::Greater || (lo == Ordering::Equal && lower.unwrap().1);
+        let below = hi == Ordering::Less || (hi == Ordering::Equal && upper.unwrap().1);
+        if above && below {
+            out.push((n.key.clone(), n.val.clone()));
+        }
+        if hi == Ordering::Less {
+            self._range(&n.right, lower, upper, out)?;
+        }
+        Ok(())
+    }
+}
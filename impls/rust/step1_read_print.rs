@@ -1,22 +1,10 @@
-#[macro_use]
-extern crate lazy_static;
-extern crate fnv;
-extern crate itertools;
-extern crate regex;
-
+extern crate mal;
 extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
-#[macro_use]
-#[allow(dead_code)]
-mod types;
-use crate::types::format_error;
-mod printer;
-mod reader;
-// TODO: figure out a way to avoid including env
-#[allow(dead_code)]
-mod env;
+use mal::reader;
+use mal::types::format_error;
 
 fn main() {
     // `()` can be used when no completer is required
@@ -2,27 +2,18 @@ use std::rc::Rc;
 //use std::collections::HashMap;
 use fnv::FnvHashMap;
 
-#[macro_use]
-extern crate lazy_static;
 extern crate fnv;
-extern crate itertools;
-extern crate regex;
-
+#[macro_use]
+extern crate mal;
 extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
-#[macro_use]
-#[allow(dead_code)]
-mod types;
-use crate::types::MalErr::ErrString;
-use crate::types::MalVal::{Hash, Int, List, Nil, Sym, Vector};
-use crate::types::{error, format_error, func, MalArgs, MalErr, MalRet, MalVal};
-mod printer;
-mod reader;
-// TODO: figure out a way to avoid including env
-#[allow(dead_code)]
-mod env;
+use mal::reader;
+use mal::types::Arity::Exactly;
+use mal::types::MalErr::ErrString;
+use mal::types::MalVal::{Hash, Int, List, Nil, Sym, Vector};
+use mal::types::{builtin, error, format_error, MalArgs, MalErr, MalRet, MalVal};
 
 pub type Env = FnvHashMap<String, MalVal>;
 
@@ -36,7 +27,7 @@ fn eval(ast: &MalVal, env: &Env) -> MalRet {
     // println!("EVAL: {}", print(&ast));
     match ast {
         Sym(sym) => Ok(env
-            .get(sym)
+            .get(sym.name())
             .ok_or_else(|| ErrString(format!("'{}' not found", sym)))?
             .clone()),
         Vector(v, _) => {
@@ -95,10 +86,22 @@ fn main() {
     }
 
     let mut repl_env = Env::default();
-    repl_env.insert("+".to_string(), func(|a: MalArgs| int_op(|i, j| i + j, a)));
-    repl_env.insert("-".to_string(), func(|a: MalArgs| int_op(|i, j| i - j, a)));
-    repl_env.insert("*".to_string(), func(|a: MalArgs| int_op(|i, j| i * j, a)));
-    repl_env.insert("/".to_string(), func(|a: MalArgs| int_op(|i, j| i / j, a)));
+    repl_env.insert(
+        "+".to_string(),
+        builtin("+", Exactly(2), |a: MalArgs| int_op(|i, j| i + j, a)),
+    );
+    repl_env.insert(
+        "-".to_string(),
+        builtin("-", Exactly(2), |a: MalArgs| int_op(|i, j| i - j, a)),
+    );
+    repl_env.insert(
+        "*".to_string(),
+        builtin("*", Exactly(2), |a: MalArgs| int_op(|i, j| i * j, a)),
+    );
+    repl_env.insert(
+        "/".to_string(),
+        builtin("/", Exactly(2), |a: MalArgs| int_op(|i, j| i / j, a)),
+    );
 
     loop {
         let readline = rl.readline("user> ");
@@ -3,25 +3,26 @@ use std::rc::Rc;
 use fnv::FnvHashMap;
 use itertools::Itertools;
 
-#[macro_use]
-extern crate lazy_static;
 extern crate fnv;
 extern crate itertools;
-extern crate regex;
-
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate mal;
 extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
-#[macro_use]
-#[allow(dead_code)]
-mod types;
-use crate::types::MalVal::{Bool, Hash, Int, List, Nil, Sym, Vector};
-use crate::types::{error, format_error, func, MalArgs, MalErr, MalRet, MalVal};
-mod env;
-mod printer;
-mod reader;
-use crate::env::{env_get, env_new, env_set, env_sets, Env};
+use mal::env::{env_get, env_new, env_set, env_sets, Env};
+use mal::reader;
+use mal::symbol::{intern, Symbol};
+use mal::types::Arity::Exactly;
+use mal::types::MalVal::{Bool, Hash, Int, List, Nil, Sym, Vector};
+use mal::types::{builtin, error, format_error, MalArgs, MalErr, MalRet, MalVal};
+
+lazy_static! {
+    static ref DEBUG_EVAL: Symbol = intern("DEBUG-EVAL");
+}
 
 // read
 fn read(str: &str) -> MalRet {
@@ -30,12 +31,12 @@ fn read(str: &str) -> MalRet {
 
 // eval
 fn eval(ast: &MalVal, env: &Env) -> MalRet {
-    match env_get(env, "DEBUG-EVAL") {
+    match env_get(env, *DEBUG_EVAL) {
         None | Some(Bool(false)) | Some(Nil) => (),
         _ => println!("EVAL: {}", print(ast)),
     }
     match ast {
-        Sym(s) => match env_get(env, s) {
+        Sym(s) => match env_get(env, *s) {
             Some(r) => Ok(r),
             None => error (&format!("'{}' not found", s)),
         }
@@ -118,10 +119,26 @@ fn main() {
     }
 
     let repl_env = env_new(None);
-    env_sets(&repl_env, "+", func(|a: MalArgs| int_op(|i, j| i + j, a)));
-    env_sets(&repl_env, "-", func(|a: MalArgs| int_op(|i, j| i - j, a)));
-    env_sets(&repl_env, "*", func(|a: MalArgs| int_op(|i, j| i * j, a)));
-    env_sets(&repl_env, "/", func(|a: MalArgs| int_op(|i, j| i / j, a)));
+    env_sets(
+        &repl_env,
+        intern("+"),
+        builtin("+", Exactly(2), |a: MalArgs| int_op(|i, j| i + j, a)),
+    );
+    env_sets(
+        &repl_env,
+        intern("-"),
+        builtin("-", Exactly(2), |a: MalArgs| int_op(|i, j| i - j, a)),
+    );
+    env_sets(
+        &repl_env,
+        intern("*"),
+        builtin("*", Exactly(2), |a: MalArgs| int_op(|i, j| i * j, a)),
+    );
+    env_sets(
+        &repl_env,
+        intern("/"),
+        builtin("/", Exactly(2), |a: MalArgs| int_op(|i, j| i / j, a)),
+    );
 
     loop {
         let readline = rl.readline("user> ");
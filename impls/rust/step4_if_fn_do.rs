@@ -3,26 +3,25 @@ use std::rc::Rc;
 use fnv::FnvHashMap;
 use itertools::Itertools;
 
-#[macro_use]
-extern crate lazy_static;
 extern crate fnv;
 extern crate itertools;
-extern crate regex;
-
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate mal;
 extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
-#[macro_use]
-mod types;
-use crate::types::MalVal::{Bool, Hash, List, MalFunc, Nil, Sym, Vector};
-use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
-mod env;
-mod printer;
-mod reader;
-use crate::env::{env_get, env_new, env_set, env_sets, Env};
-#[macro_use]
-mod core;
+use mal::env::{env_get, env_new, env_set, env_sets, Env};
+use mal::symbol::{intern, Symbol};
+use mal::types::MalVal::{Bool, Hash, List, MalFunc, Nil, Sym, Vector};
+use mal::types::{error, format_error, Context, FnCount, MalArgs, MalErr, MalRet, MalVal};
+use mal::{core, reader};
+
+lazy_static! {
+    static ref DEBUG_EVAL: Symbol = intern("DEBUG-EVAL");
+}
 
 // read
 fn read(str: &str) -> MalRet {
@@ -31,12 +30,12 @@ fn read(str: &str) -> MalRet {
 
 // eval
 fn eval(ast: &MalVal, env: &Env) -> MalRet {
-    match env_get(env, "DEBUG-EVAL") {
+    match env_get(env, *DEBUG_EVAL) {
         None | Some(Bool(false)) | Some(Nil) => (),
         _ => println!("EVAL: {}", print(ast)),
     }
     match ast {
-        Sym(s) => match env_get(env, s) {
+        Sym(s) => match env_get(env, *s) {
             Some(r) => Ok(r),
             None => error (&format!("'{}' not found", s)),
         }
@@ -103,6 +102,8 @@ fn eval(ast: &MalVal, env: &Env) -> MalRet {
                         params: Rc::new(a1),
                         is_macro: false,
                         meta: Rc::new(Nil),
+                        code: None,
+                        counted: FnCount::new(),
                     })
                 }
                 _ => {
@@ -111,7 +112,7 @@ fn eval(ast: &MalVal, env: &Env) -> MalRet {
                     for i in 1..l.len() {
                         args.push(eval(&l[i], env)?);
                     }
-                    f.apply(args)
+                    f.apply_ctx(args, Some(&Context { env, eval }))
                 },
             }
         }
@@ -149,7 +150,7 @@ fn main() {
     // core.rs: defined using rust
     let repl_env = env_new(None);
     for (k, v) in core::ns() {
-        env_sets(&repl_env, k, v);
+        env_sets(&repl_env, intern(k), v);
     }
 
     // core.mal: defined using the language itself
@@ -3,26 +3,25 @@ use std::rc::Rc;
 use fnv::FnvHashMap;
 use itertools::Itertools;
 
-#[macro_use]
-extern crate lazy_static;
 extern crate fnv;
 extern crate itertools;
-extern crate regex;
-
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate mal;
 extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
-#[macro_use]
-mod types;
-use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
-mod env;
-mod printer;
-mod reader;
-use crate::env::{env_bind, env_find_repl, env_get, env_new, env_set, env_sets, Env};
-#[macro_use]
-mod core;
+use mal::env::{env_bind, env_find_repl, env_get, env_new, env_set, env_sets, Env};
+use mal::symbol::{intern, Symbol};
+use mal::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Str, Sym, Vector};
+use mal::types::{error, format_error, Context, FnCount, MalArgs, MalErr, MalRet, MalVal};
+use mal::{core, reader};
+
+lazy_static! {
+    static ref DEBUG_EVAL: Symbol = intern("DEBUG-EVAL");
+}
 
 // read
 fn read(str: &str) -> MalRet {
@@ -39,12 +38,12 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
     let mut live_env;
 
     'tco: loop {
-        match env_get(env, "DEBUG-EVAL") {
+        match env_get(env, *DEBUG_EVAL) {
             None | Some(Bool(false)) | Some(Nil) => (),
             _ => println!("EVAL: {}", print(ast)),
         }
         match ast {
-        Sym(s) => match env_get(env, s) {
+        Sym(s) => match env_get(env, *s) {
             Some(r) => return Ok(r),
             None => return error(&format!("'{}' not found", s)),
         }
@@ -124,6 +123,8 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                             params: Rc::new(a1),
                             is_macro: false,
                             meta: Rc::new(Nil),
+                            code: None,
+                            counted: FnCount::new(),
                         })
                     }
                     Sym(a0sym) if a0sym == "eval" => {
@@ -141,7 +142,7 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                                     for i in 1..l.len() {
                                         args.push(eval(&l[i], env)?);
                                     }
-                                    return f.apply(args);
+                                    return f.apply_ctx(args, Some(&Context { env, eval }));
                                 }
                                 Ok(MalFunc {
                                     ast: mast,
@@ -204,9 +205,9 @@ fn main() {
     // core.rs: defined using rust
     let repl_env = env_new(None);
     for (k, v) in core::ns() {
-        env_sets(&repl_env, k, v);
+        env_sets(&repl_env, intern(k), v);
     }
-    env_sets(&repl_env, "*ARGV*", list!(args.map(Str).collect()));
+    env_sets(&repl_env, intern("*ARGV*"), list!(args.map(Str).collect()));
 
     // core.mal: defined using the language itself
     re("(def! not (fn* (a) (if a false true)))", &repl_env);
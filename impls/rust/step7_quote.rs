@@ -3,26 +3,25 @@ use std::rc::Rc;
 use fnv::FnvHashMap;
 use itertools::Itertools;
 
-#[macro_use]
-extern crate lazy_static;
 extern crate fnv;
 extern crate itertools;
-extern crate regex;
-
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate mal;
 extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
-#[macro_use]
-mod types;
-use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
-mod env;
-mod printer;
-mod reader;
-use crate::env::{env_bind, env_find_repl, env_get, env_new, env_set, env_sets, Env};
-#[macro_use]
-mod core;
+use mal::env::{env_bind, env_find_repl, env_get, env_new, env_set, env_sets, Env};
+use mal::symbol::{intern, Symbol};
+use mal::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Str, Sym, Vector};
+use mal::types::{error, format_error, Context, FnCount, MalArgs, MalErr, MalRet, MalVal};
+use mal::{core, reader};
+
+lazy_static! {
+    static ref DEBUG_EVAL: Symbol = intern("DEBUG-EVAL");
+}
 
 // read
 fn read(str: &str) -> MalRet {
@@ -38,13 +37,13 @@ fn qq_iter(elts: &MalArgs) -> MalVal {
             if v.len() == 2 {
                 if let Sym(ref s) = v[0] {
                     if s == "splice-unquote" {
-                        acc = list![Sym("concat".to_string()), v[1].clone(), acc];
+                        acc = list![Sym(intern("concat")), v[1].clone(), acc];
                         continue;
                     }
                 }
             }
         }
-        acc = list![Sym("cons".to_string()), quasiquote(elt), acc];
+        acc = list![Sym(intern("cons")), quasiquote(elt), acc];
     }
     acc
 }
@@ -61,8 +60,8 @@ fn quasiquote(ast: &MalVal) -> MalVal {
             }
             qq_iter(v)
         },
-        Vector(v, _) => list![Sym("vec".to_string()), qq_iter(v)],
-        Hash(_, _) | Sym(_)=> list![Sym("quote".to_string()), ast.clone()],
+        Vector(v, _) => list![Sym(intern("vec")), qq_iter(v)],
+        Hash(_, _) | Sym(_)=> list![Sym(intern("quote")), ast.clone()],
         _ => ast.clone(),
     }
 }
@@ -76,12 +75,12 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
     let mut live_env;
 
     'tco: loop {
-        match env_get(env, "DEBUG-EVAL") {
+        match env_get(env, *DEBUG_EVAL) {
             None | Some(Bool(false)) | Some(Nil) => (),
             _ => println!("EVAL: {}", print(ast)),
         }
         match ast {
-        Sym(s) => match env_get(env, s) {
+        Sym(s) => match env_get(env, *s) {
             Some(r) => return Ok(r),
             None => return error(&format!("'{}' not found", s)),
         }
@@ -167,6 +166,8 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                             params: Rc::new(a1),
                             is_macro: false,
                             meta: Rc::new(Nil),
+                            code: None,
+                            counted: FnCount::new(),
                         })
                     }
                     Sym(a0sym) if a0sym == "eval" => {
@@ -184,7 +185,7 @@ fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                                     for i in 1..l.len() {
                                         args.push(eval(&l[i], env)?);
                                     }
-                                    return f.apply(args);
+                                    return f.apply_ctx(args, Some(&Context { env, eval }));
                                 }
                                 Ok(MalFunc {
                                     ast: mast,
@@ -247,9 +248,9 @@ fn main() {
     // core.rs: defined using rust
     let repl_env = env_new(None);
     for (k, v) in core::ns() {
-        env_sets(&repl_env, k, v);
+        env_sets(&repl_env, intern(k), v);
     }
-    env_sets(&repl_env, "*ARGV*", list!(args.map(Str).collect()));
+    env_sets(&repl_env, intern("*ARGV*"), list!(args.map(Str).collect()));
 
     // core.mal: defined using the language itself
     re("(def! not (fn* (a) (if a false true)))", &repl_env);
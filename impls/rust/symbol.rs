@@ -0,0 +1,231 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::rc::Rc;
+use std::sync::Mutex;
+
+use fnv::FnvHashMap;
+
+// Interned symbol and keyword names. Each distinct name is stored once
+// for the life of the process and gets a small id, so that symbols
+// compare and hash as integers. The name is kept next to the id so that
+// printing a symbol needs no lookup.
+//
+// As names are never freed, those that come from what a program reads
+// or makes at run time are interned with try_intern, which refuses new
+// ones once the table is full, and those of data with try_intern_data,
+// which leaves half the table for code. Those of the interpreter itself
+// are few and always interned. The table is shared by every interpreter
+// in the process, so keywords made from data are not interned at all,
+// unless their name already is.
+
+#[derive(Clone, Copy)]
+pub struct Symbol {
+    id: u32,
+    name: &'static str,
+}
+
+// The most names try_intern adds, and bytes in all of them; data may
+// have half of each
+pub const MAX_SYMBOLS: usize = 1 << 20;
+pub const MAX_SYMBOL_BYTES: usize = 32 << 20;
+
+#[derive(Default)]
+struct Table {
+    syms: FnvHashMap<&'static str, Symbol>,
+    bytes: usize,
+}
+
+lazy_static! {
+    static ref SYMBOLS: Mutex<Table> = Mutex::new(Table::default());
+}
+
+pub fn intern(name: &str) -> Symbol {
+    lookup_or_add(name, None).unwrap()
+}
+
+// Like intern, but None rather than a new name if the table is full
+pub fn try_intern(name: &str) -> Option<Symbol> {
+    lookup_or_add(name, Some(1))
+}
+
+// Like try_intern, but None once the table is half full
+pub fn try_intern_data(name: &str) -> Option<Symbol> {
+    lookup_or_add(name, Some(2))
+}
+
+// The symbol for name if it is interned already
+pub fn lookup(name: &str) -> Option<Symbol> {
+    SYMBOLS.lock().unwrap().syms.get(name).copied()
+}
+
+// share: the part of the table that may be filled, e.g. 2 for half
+fn lookup_or_add(name: &str, share: Option<usize>) -> Option<Symbol> {
+    let mut table = SYMBOLS.lock().unwrap();
+    if let Some(s) = table.syms.get(name) {
+        return Some(*s);
+    }
+    if let Some(share) = share {
+        if table.syms.len() >= MAX_SYMBOLS / share
+            || table.bytes + name.len() > MAX_SYMBOL_BYTES / share
+        {
+            return None;
+        }
+    }
+    let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+    let s = Symbol {
+        id: table.syms.len() as u32,
+        name,
+    };
+    table.syms.insert(name, s);
+    table.bytes += name.len();
+    Some(s)
+}
+
+impl Symbol {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Symbol) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state)
+    }
+}
+
+// by name, as for strings
+impl Ord for Symbol {
+    fn cmp(&self, other: &Symbol) -> Ordering {
+        self.name.cmp(other.name)
+    }
+}
+
+impl PartialOrd for Symbol {
+    fn partial_cmp(&self, other: &Symbol) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        self.name == other
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        self.name == *other
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.name
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name)
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name)
+    }
+}
+
+// A keyword's name. Keywords read from code are interned as symbols
+// are, while those made from data keep a name of their own unless it is
+// interned already, so that they cannot fill the table. Either way they
+// are equal when their names are.
+#[derive(Clone)]
+pub enum Keyword {
+    Interned(Symbol),
+    Uninterned(Rc<str>),
+}
+
+impl Keyword {
+    // For a keyword in code: interned unless the table is full
+    pub fn new(name: &str) -> Keyword {
+        match try_intern(name) {
+            Some(s) => Keyword::Interned(s),
+            None => Keyword::Uninterned(name.into()),
+        }
+    }
+
+    // For a keyword in data
+    pub fn from_data(name: &str) -> Keyword {
+        match lookup(name) {
+            Some(s) => Keyword::Interned(s),
+            None => Keyword::Uninterned(name.into()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Keyword::Interned(s) => s.name(),
+            Keyword::Uninterned(name) => name,
+        }
+    }
+}
+
+impl PartialEq for Keyword {
+    fn eq(&self, other: &Keyword) -> bool {
+        match (self, other) {
+            (Keyword::Interned(a), Keyword::Interned(b)) => a == b,
+            _ => self.name() == other.name(),
+        }
+    }
+}
+
+impl Eq for Keyword {}
+
+impl Ord for Keyword {
+    fn cmp(&self, other: &Keyword) -> Ordering {
+        self.name().cmp(other.name())
+    }
+}
+
+impl PartialOrd for Keyword {
+    fn partial_cmp(&self, other: &Keyword) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Deref for Keyword {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.name()
+    }
+}
+
+impl fmt::Display for Keyword {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl fmt::Debug for Keyword {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
@@ -0,0 +1,36 @@
+// Helpers shared by the test binaries, not all of which use all of them
+#![allow(dead_code)]
+
+use std::process::Command;
+
+use mal::types::format_error;
+use mal::Interpreter;
+
+// What evaluating src prints at the REPL
+pub fn eval(interp: &Interpreter, src: &str) -> String {
+    match interp.eval_str(src) {
+        Ok(v) => v.pr_str(true),
+        Err(e) => format!("Error: {}", format_error(e)),
+    }
+}
+
+pub const LOOP: &str = "(def! count-down (fn* [n] (if (> n 0) (count-down (- n 1)) :done)))";
+
+// The output of running the stepA binary on src with flags
+pub fn run(flags: &[&str], src: &str) -> (Option<i32>, String) {
+    let path = std::env::temp_dir().join(format!(
+        "mal-run-{}-{}.mal",
+        std::process::id(),
+        flags.join("")
+    ));
+    std::fs::write(&path, src).unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_stepA_mal"))
+        .args(flags)
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let mut text = String::from_utf8_lossy(&out.stdout).to_string();
+    text.push_str(&String::from_utf8_lossy(&out.stderr));
+    (out.status.code(), text)
+}
@@ -0,0 +1,114 @@
+extern crate mal;
+
+mod common;
+
+use std::collections::HashMap;
+
+use common::eval;
+use mal::types::format_error;
+use mal::{FromMal, Interpreter, IntoMal, MalVal};
+
+fn round_trip<T: IntoMal + FromMal + Clone + PartialEq + std::fmt::Debug>(v: T, printed: &str) {
+    let m = v.clone().into_mal().unwrap();
+    assert_eq!(m.pr_str(true), printed);
+    assert_eq!(T::from_mal(&m).unwrap(), v);
+}
+
+#[test]
+fn values_round_trip() {
+    round_trip(true, "true");
+    round_trip(-3i64, "-3");
+    round_trip(200u8, "200");
+    round_trip("hi".to_string(), "\"hi\"");
+    round_trip(Some(1i32), "1");
+    round_trip(None::<i32>, "nil");
+    round_trip(vec![1usize, 2], "[1 2]");
+    round_trip((1i64, "a".to_string()), "[1 \"a\"]");
+    let mut hm = HashMap::new();
+    hm.insert("a".to_string(), vec![true]);
+    round_trip(hm, "{\"a\" [true]}");
+}
+
+#[test]
+fn from_mal_errors() {
+    let interp = Interpreter::new();
+    let err =
+        |src: &str, f: fn(&MalVal) -> Option<String>| f(&interp.eval_str(src).unwrap()).unwrap();
+    let as_u8 = |v: &MalVal| u8::from_mal(v).err().map(format_error);
+    assert_eq!(err("256", as_u8), "expected int in range of u8, got 256");
+    assert_eq!(err("-1", as_u8), "expected int in range of u8, got -1");
+    assert_eq!(err("\"1\"", as_u8), "expected int, got \"1\"");
+    let as_string = |v: &MalVal| String::from_mal(v).err().map(format_error);
+    assert_eq!(err(":a", as_string), "expected string, got :a");
+    let as_pair = |v: &MalVal| <(i64, i64)>::from_mal(v).err().map(format_error);
+    assert_eq!(
+        err("[1 2 3]", as_pair),
+        "expected sequence of length 2, got [1 2 3]"
+    );
+    // keyword keys come out as their names
+    let hm = HashMap::<String, i64>::from_mal(&interp.eval_str("{:a 1}").unwrap()).unwrap();
+    assert_eq!(hm.get("a"), Some(&1));
+    let as_map = |v: &MalVal| HashMap::<String, i64>::from_mal(v).err().map(format_error);
+    assert_eq!(
+        err("{:a 1 \"a\" 2}", as_map),
+        "keys \"a\" and :a are the same"
+    );
+    assert_eq!(
+        err("{[1] 2}", as_map),
+        "expected string or keyword key, got [1]"
+    );
+}
+
+#[test]
+fn into_mal_refuses_what_does_not_fit() {
+    let big = i64::MAX as u64 as usize + 1;
+    assert_eq!(
+        format_error(big.into_mal().unwrap_err()),
+        "9223372036854775808 is too big for an int"
+    );
+    assert!(vec![1, big].into_mal().is_err());
+    assert_eq!(u32::MAX.into_mal().unwrap().pr_str(true), "4294967295");
+    let interp = Interpreter::new();
+    interp.register_typed("big", move || big);
+    assert_eq!(
+        eval(&interp, "(big)"),
+        "Error: 9223372036854775808 is too big for an int"
+    );
+}
+
+#[test]
+fn register_typed() {
+    let interp = Interpreter::new();
+    interp.register_typed("add", |a: i64, b: i64| a + b);
+    interp.register_typed("half", |a: i64| {
+        if a % 2 == 0 {
+            Ok(a / 2)
+        } else {
+            Err(format!("{} is odd", a))
+        }
+    });
+    interp.register_typed("greet", |name: Option<String>| {
+        format!("hello {}", name.unwrap_or_else(|| "you".to_string()))
+    });
+    assert_eq!(eval(&interp, "(add 1 2)"), "3");
+    assert_eq!(eval(&interp, "(half 4)"), "2");
+    assert_eq!(eval(&interp, "(half 3)"), "Error: 3 is odd");
+    assert_eq!(eval(&interp, "(greet nil)"), "\"hello you\"");
+    assert_eq!(eval(&interp, "(greet \"me\")"), "\"hello me\"");
+    assert_eq!(
+        eval(&interp, "(add 1)"),
+        "Error: wrong number of args (1) passed to add"
+    );
+    assert_eq!(
+        eval(&interp, "(add 1 2 3)"),
+        "Error: wrong number of args (3) passed to add"
+    );
+    assert_eq!(
+        eval(&interp, "(add 1 \"2\")"),
+        "Error: add: argument 2: expected int, got \"2\""
+    );
+    assert_eq!(
+        eval(&interp, "(greet 1)"),
+        "Error: greet: argument 1: expected string, got 1"
+    );
+}
@@ -0,0 +1,84 @@
+extern crate mal;
+
+use std::fs;
+use std::path::PathBuf;
+
+use mal::{coverage_lcov, set_coverage, Engine, Interpreter};
+
+const SRC: &str = "(def! f (fn* [x]
+  (cond
+    x 1
+    (= x 0) 2)))
+";
+
+// A file with SRC in it, named after the test
+fn source(name: &str) -> String {
+    let dir: PathBuf = std::env::temp_dir().join(format!("mal-coverage-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(format!("{}.mal", name));
+    fs::write(&path, SRC).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+fn load(interp: &Interpreter, path: &str) {
+    interp
+        .eval_str(&format!("(load-file \"{}\")", path))
+        .unwrap();
+}
+
+#[test]
+fn restarting_keeps_compiled_sites() {
+    for engine in [Engine::Tree, Engine::Bytecode] {
+        let path = source(&format!("restart-{:?}", engine));
+        let interp = Interpreter::with_engine(engine);
+        set_coverage(true);
+        load(&interp, &path);
+        set_coverage(true);
+        assert_eq!(interp.eval_str("(f true)").unwrap().pr_str(true), "1");
+        set_coverage(false);
+        let lcov = coverage_lcov();
+        assert!(lcov.contains("DA:2,1"), "{}", lcov);
+    }
+}
+
+#[test]
+fn another_interpreter_keeps_compiled_sites() {
+    let path = source("another");
+    let first = Interpreter::new();
+    set_coverage(true);
+    load(&first, &path);
+    // which restarts coverage for the thread, as the other one runs
+    let second = Interpreter::new();
+    set_coverage(true);
+    assert_eq!(second.eval_str("(+ 1 2)").unwrap().pr_str(true), "3");
+    assert_eq!(first.eval_str("(f true)").unwrap().pr_str(true), "1");
+    set_coverage(false);
+}
+
+#[test]
+fn branches_of_cond() {
+    for engine in [Engine::Tree, Engine::Bytecode] {
+        let path = source(&format!("branches-{:?}", engine));
+        let interp = Interpreter::with_engine(engine);
+        set_coverage(true);
+        load(&interp, &path);
+        interp.eval_str("(f true) (f true)").unwrap();
+        set_coverage(false);
+        let lcov = coverage_lcov();
+        let file = lcov.split("SF:").find(|r| r.starts_with(&path)).unwrap();
+        let branches: Vec<&str> = file.lines().filter(|l| l.starts_with("BRDA:")).collect();
+        // the then and else of the if x, and of the if (= x 0)
+        assert_eq!(branches.len(), 4, "{}", file);
+        assert!(
+            branches.iter().all(|b| b.starts_with("BRDA:2,")),
+            "{}",
+            file
+        );
+        let taken: Vec<&str> = branches
+            .iter()
+            .map(|b| b.rsplit(',').next().unwrap())
+            .collect();
+        assert_eq!(taken, ["0", "0", "2", "0"], "{}", file);
+        assert!(file.contains("BRF:4\nBRH:1\n"), "{}", file);
+    }
+}
@@ -0,0 +1,155 @@
+extern crate mal;
+
+mod common;
+
+use std::rc::Rc;
+
+use common::{eval, run, LOOP};
+use mal::limits::is_exceeded;
+use mal::symbol::{intern, Keyword};
+use mal::types::format_error;
+use mal::{Arity, Engine, Interpreter, Limits, MalVal};
+
+#[test]
+fn eval_str_returns_the_last_value() {
+    for engine in [Engine::Tree, Engine::Bytecode] {
+        let interp = Interpreter::with_engine(engine);
+        assert_eq!(eval(&interp, "(def! x 2) (+ x 3)"), "5");
+        assert_eq!(eval(&interp, ""), "nil");
+        assert_eq!(eval(&interp, "(throw \"oops\")"), "Error: \"oops\"");
+        assert_eq!(eval(&interp, "(+ 1"), "Error: expected ')', got EOF");
+    }
+}
+
+#[test]
+fn define_lookup_and_call() {
+    let interp = Interpreter::new();
+    interp.define("x", MalVal::Int(40));
+    assert_eq!(eval(&interp, "(+ x 2)"), "42");
+    assert_eq!(interp.lookup("x").unwrap().pr_str(true), "40");
+    assert!(interp.lookup("nope").is_none());
+    eval(&interp, "(def! add (fn* [a b] (+ a b)))");
+    let sum = interp.call("add", vec![MalVal::Int(1), MalVal::Int(2)]);
+    assert_eq!(sum.unwrap().pr_str(true), "3");
+    let missing = interp.call("nope", vec![]).unwrap_err();
+    assert_eq!(format_error(missing), "'nope' not found");
+}
+
+#[test]
+fn register_fn() {
+    let interp = Interpreter::new();
+    interp.register_fn("twice", Arity::Exactly(1), |a| match a[0] {
+        MalVal::Int(n) => Ok(MalVal::Int(2 * n)),
+        _ => mal::types::error("twice: expecting an int"),
+    });
+    assert_eq!(eval(&interp, "(map twice [1 2 3])"), "(2 4 6)");
+    assert_eq!(
+        eval(&interp, "(twice \"a\")"),
+        "Error: twice: expecting an int"
+    );
+    assert!(eval(&interp, "(twice 1 2)").starts_with("Error: "));
+}
+
+#[test]
+fn instances_have_their_own_env() {
+    let a = Interpreter::new();
+    let b = Interpreter::new();
+    eval(&a, "(def! x 1)");
+    assert_eq!(eval(&b, "x"), "Error: 'x' not found");
+}
+
+#[test]
+fn instances_have_their_own_limits() {
+    let limited = Interpreter::new();
+    let free = Interpreter::new();
+    limited.set_limits(Limits {
+        max_steps: Some(100),
+        ..Limits::default()
+    });
+    assert_eq!(free.limits(), Limits::default());
+    for interp in [&limited, &free] {
+        eval(interp, LOOP);
+    }
+    assert_eq!(eval(&free, "(count-down 1000)"), ":done");
+    let err = limited.eval_str("(count-down 1000)").unwrap_err();
+    assert!(is_exceeded(&err), "{}", format_error(err));
+}
+
+#[test]
+fn nested_evaluation_of_another_instance() {
+    let limited = Rc::new(Interpreter::new());
+    let free = Rc::new(Interpreter::new());
+    limited.set_limits(Limits {
+        max_steps: Some(100),
+        ..Limits::default()
+    });
+    eval(&free, LOOP);
+    let inner = free.clone();
+    limited.register_fn("run-free", Arity::Exactly(0), move |_| {
+        inner.eval_str("(count-down 1000)")
+    });
+    // the steps of free do not count against limited, whose own limit
+    // is back in place afterwards
+    assert_eq!(eval(&limited, "(run-free)"), ":done");
+    eval(&limited, LOOP);
+    let err = limited
+        .eval_str("(do (run-free) (count-down 1000))")
+        .unwrap_err();
+    assert!(is_exceeded(&err), "{}", format_error(err));
+}
+
+#[test]
+fn builtins_called_from_rust_get_the_repl_context() {
+    let interp = Interpreter::new();
+    interp.register_fn("call-with", Arity::Exactly(2), |a| {
+        a[0].apply(vec![a[1].clone()])
+    });
+    assert_eq!(eval(&interp, "(call-with eval '(+ 1 2))"), "3");
+    assert_eq!(eval(&interp, "(let* [x 1] (call-with resolve 'x))"), "nil");
+    let f = interp.lookup("eval").unwrap();
+    let res = f.apply(vec![MalVal::Int(1)]).unwrap_err();
+    // outside of an evaluation there is none
+    assert_eq!(
+        format_error(res),
+        "eval can only be called from the evaluator"
+    );
+}
+
+#[test]
+fn keywords_in_code_are_interned() {
+    let interp = Interpreter::new();
+    match interp.eval_str(":abc").unwrap() {
+        MalVal::Kw(Keyword::Interned(k)) => assert_eq!(k, intern("abc")),
+        v => panic!("not an interned keyword: {}", v.pr_str(true)),
+    }
+    // those made from data are not, but equal those that are
+    match interp.eval_str("(keyword \"not-in-code\")").unwrap() {
+        MalVal::Kw(Keyword::Uninterned(name)) => assert_eq!(&*name, "not-in-code"),
+        v => panic!("not an uninterned keyword: {}", v.pr_str(true)),
+    }
+    assert_eq!(eval(&interp, "(= :abc (keyword \"abc\"))"), "true");
+    assert_eq!(eval(&interp, "(= :xyz (edn-read-string \":xyz\"))"), "true");
+    assert_eq!(eval(&interp, "(= :abc \"abc\")"), "false");
+    assert_eq!(eval(&interp, "(keys {:a 1 \"b\" 2})"), "(\"b\" :a)");
+    assert_eq!(
+        eval(&interp, "(map keyword? (keys {:a 1 \"b\" 2}))"),
+        "(false true)"
+    );
+}
+
+#[test]
+fn stats_flag() {
+    let src = "(def! mk (fn* [n] (let* [f (fn* [x] (if (= x 0) n (f (- x 1))))] (f 2))))
+               (mk 5) (gc) (mk 5) (gc)";
+    let (code, out) = run(&["--stats"], src);
+    assert_eq!(code, Some(0));
+    let stat = |name: &str| -> usize {
+        let line = out.lines().find(|l| l.starts_with(name));
+        let line = line.unwrap_or_else(|| panic!("no {} in\n{}", name, out));
+        line[name.len()..].trim().parse().unwrap()
+    };
+    assert!(stat("calls") > 0, "{}", out);
+    // the collector's totals over both runs
+    assert_eq!(stat("gc-runs"), 2, "{}", out);
+    assert_eq!(stat("freed-envs"), 4, "{}", out);
+}
@@ -0,0 +1,277 @@
+extern crate mal;
+
+mod common;
+
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+use common::{eval, run, LOOP};
+use mal::limits::is_exceeded;
+use mal::types::format_error;
+use mal::{Arity, Engine, Interpreter, Limits, MalVal};
+
+#[test]
+fn interrupted_then_reset() {
+    for engine in [Engine::Tree, Engine::Bytecode] {
+        let interp = Interpreter::with_engine(engine);
+        let stop = interp.interrupt();
+        interp.register_fn("stop", Arity::Exactly(0), move |_| {
+            stop.interrupt();
+            Ok(MalVal::Nil)
+        });
+        eval(&interp, LOOP);
+        assert_eq!(
+            eval(&interp, "(do (stop) (count-down 10000))"),
+            "Error: interrupted"
+        );
+        assert!(!interp.interrupt().interrupted());
+        assert_eq!(eval(&interp, "(count-down 10)"), ":done");
+        // try* catches it, but the steps after raise it again
+        let caught = "(do (stop) (try* (count-down 10000) (catch* e (count-down 10))))";
+        assert_eq!(eval(&interp, caught), "Error: interrupted");
+    }
+}
+
+#[test]
+fn interrupted_from_another_thread() {
+    for engine in [Engine::Tree, Engine::Bytecode] {
+        let interp = Interpreter::with_engine(engine);
+        let stop = interp.interrupt();
+        let stopper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            stop.interrupt();
+        });
+        let forever = "(def! forever (fn* [] (forever))) (forever)";
+        assert_eq!(eval(&interp, forever), "Error: interrupted");
+        stopper.join().unwrap();
+    }
+}
+
+#[test]
+fn set_before_an_evaluation_is_cleared() {
+    let interp = Interpreter::new();
+    interp.interrupt().interrupt();
+    assert_eq!(eval(&interp, "(+ 1 2)"), "3");
+    assert!(!interp.interrupt().interrupted());
+}
+
+#[test]
+fn evaluations_elsewhere_leave_it_set() {
+    let interp = Interpreter::new();
+    let other = Rc::new(Interpreter::new());
+    eval(&other, LOOP);
+    let stop = interp.interrupt();
+    let nested = other.clone();
+    interp.register_fn("stop-then-run-others", Arity::Exactly(0), move |_| {
+        stop.interrupt();
+        // on another thread
+        thread::spawn(|| Interpreter::new().eval_str("(+ 1 2)").is_ok())
+            .join()
+            .unwrap();
+        // and by another interpreter on this one, which is not stopped
+        nested.eval_str("(count-down 10000)")
+    });
+    eval(&interp, LOOP);
+    let src = "(do (stop-then-run-others) (count-down 10000))";
+    assert_eq!(eval(&interp, src), "Error: interrupted");
+}
+
+fn limited(limits: Limits) -> Interpreter {
+    let interp = Interpreter::new();
+    eval(&interp, LOOP);
+    eval(&interp, "(def! forever (fn* [] (forever)))");
+    eval(
+        &interp,
+        "(def! upto (fn* [n acc] (if (= n 0) acc (upto (- n 1) (conj acc n)))))",
+    );
+    eval(&interp, "(def! xs (upto 20 []))");
+    eval(&interp, "(def! strs (map str xs))");
+    eval(&interp, "(def! hm (apply hash-map strs))");
+    interp.set_limits(limits);
+    interp
+}
+
+fn exceeded(interp: &Interpreter, src: &str) -> String {
+    match interp.eval_str(src) {
+        Ok(v) => panic!("{} gave {}", src, v.pr_str(true)),
+        Err(e) => {
+            let exceeded = is_exceeded(&e);
+            let msg = format_error(e);
+            assert!(exceeded, "{}: {}", src, msg);
+            msg
+        }
+    }
+}
+
+#[test]
+fn max_steps() {
+    let interp = limited(Limits {
+        max_steps: Some(500),
+        ..Limits::default()
+    });
+    assert_eq!(eval(&interp, "(count-down 10)"), ":done");
+    assert_eq!(
+        exceeded(&interp, "(count-down 1000)"),
+        "limit exceeded: more than 500 steps"
+    );
+    // the steps of functions a builtin calls count too, though they make
+    // no calls themselves
+    let mapped = "(map (fn* [x] (let* [y x z y] [x y z])) (concat xs xs xs xs xs xs))";
+    exceeded(&interp, mapped);
+    // each evaluation starts afresh
+    assert_eq!(eval(&interp, "(count-down 10)"), ":done");
+}
+
+#[test]
+fn max_depth() {
+    for engine in [Engine::Tree, Engine::Bytecode] {
+        let interp = Interpreter::with_engine(engine);
+        eval(&interp, LOOP);
+        eval(
+            &interp,
+            "(def! deep (fn* [n] (if (> n 0) (+ 1 (deep (- n 1))) 0)))",
+        );
+        eval(&interp, "(def! runaway (fn* [n] (+ 1 (runaway n))))");
+        interp.set_limits(Limits {
+            max_depth: Some(50),
+            ..Limits::default()
+        });
+        assert_eq!(eval(&interp, "(deep 40)"), "40");
+        assert_eq!(
+            exceeded(&interp, "(runaway 1)"),
+            "limit exceeded: more than 50 calls deep"
+        );
+        // tail calls, and those of builtins, do not go deeper
+        assert_eq!(eval(&interp, "(count-down 1000)"), ":done");
+        assert_eq!(eval(&interp, "(map deep [10 20 30])"), "(10 20 30)");
+        // and the depth is back where it was once it fails
+        let caught = "(try* (runaway 1) (catch* e (deep 40)))";
+        assert_eq!(eval(&interp, caught), "40");
+    }
+}
+
+#[test]
+fn timeout() {
+    let interp = limited(Limits {
+        timeout: Some(Duration::from_millis(50)),
+        ..Limits::default()
+    });
+    assert_eq!(
+        exceeded(&interp, "(forever)"),
+        "limit exceeded: took over 50 ms"
+    );
+    // try* cannot keep it going
+    exceeded(&interp, "(try* (forever) (catch* e (forever)))");
+    assert_eq!(eval(&interp, "(count-down 10)"), ":done");
+}
+
+#[test]
+fn max_size() {
+    let path = std::env::temp_dir().join(format!("mal-limits-{}.txt", std::process::id()));
+    std::fs::write(&path, "0123456789").unwrap();
+    let interp = limited(Limits {
+        max_size: Some(5),
+        ..Limits::default()
+    });
+    interp.define("path", MalVal::Str(path.to_str().unwrap().to_string()));
+    let srcs = [
+        "(apply list xs)",
+        "(apply vector xs)",
+        "(vec (apply list xs))",
+        "(apply hash-map strs)",
+        "(apply sorted-map xs)",
+        "(apply sorted-map-by compare xs)",
+        "(apply sorted-set xs)",
+        "(apply sorted-set-by compare xs)",
+        "(seq xs)",
+        "(seq \"0123456789\")",
+        "(map (fn* [x] x) xs)",
+        "(keys hm)",
+        "(vals hm)",
+        "(read-string \"(1 2 3)\")",
+        "(slurp path)",
+        "(json-parse \"[1, 2]\")",
+        "(edn-read-string \"[1 2 3]\")",
+        "(str \"abc\" \"def\")",
+        "(pr-str xs)",
+        "(json-stringify xs)",
+        "(edn-pr-str xs)",
+        "(cons 1 (upto 5 []))",
+        "(concat xs)",
+        "(conj (upto 5 []) 1)",
+        "(assoc {} :a 1 :b 2 :c 3 :d 4 :e 5 :f 6)",
+    ];
+    for src in srcs.iter() {
+        let err = exceeded(&interp, src);
+        assert!(err.starts_with("limit exceeded: size "), "{}: {}", src, err);
+    }
+    assert_eq!(eval(&interp, "(count (upto 5 []))"), "5");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn is_exceeded_only_for_limits() {
+    let interp = limited(Limits {
+        max_steps: Some(500),
+        ..Limits::default()
+    });
+    for src in [
+        "(throw \"limit exceeded: no\")",
+        "(nth [] 1)",
+        "(undefined)",
+    ]
+    .iter()
+    {
+        let err = interp.eval_str(src).unwrap_err();
+        assert!(!is_exceeded(&err), "{}", src);
+    }
+}
+
+#[test]
+fn command_line_flags() {
+    let forever = "(def! forever (fn* [] (forever))) (forever)";
+    let (code, out) = run(&["--max-steps", "1000"], forever);
+    assert_eq!(code, Some(1));
+    assert!(
+        out.contains("Error: limit exceeded: more than 1000 steps"),
+        "{}",
+        out
+    );
+    let (code, out) = run(&["--vm", "--timeout", "50"], forever);
+    assert_eq!(code, Some(1));
+    assert!(
+        out.contains("Error: limit exceeded: took over 50 ms"),
+        "{}",
+        out
+    );
+    let (code, out) = run(&["--max-size", "3"], "(prn (list 1 2 3 4))");
+    assert_eq!(code, Some(1));
+    assert!(
+        out.contains("Error: limit exceeded: size 4 is over 3"),
+        "{}",
+        out
+    );
+    let (code, out) = run(&["--max-size", "3"], "(prn (list 1 2 3))");
+    assert_eq!(code, Some(0));
+    assert!(out.contains("(1 2 3)"), "{}", out);
+    let runaway = "(def! f (fn* (n) (+ 1 (f n)))) (f 1)";
+    let (code, out) = run(&["--max-depth", "100"], runaway);
+    assert_eq!(code, Some(1));
+    assert!(
+        out.contains("Error: limit exceeded: more than 100 calls deep"),
+        "{}",
+        out
+    );
+    // any limit brings one on the depth, rather than a stack overflow
+    let (code, out) = run(&["--max-steps", "10000000", "--timeout", "5000"], runaway);
+    assert_eq!(code, Some(1));
+    assert!(
+        out.contains("Error: limit exceeded: more than 500 calls deep"),
+        "{}",
+        out
+    );
+    let (code, out) = run(&["--max-steps", "many"], "nil");
+    assert_eq!(code, Some(2));
+    assert!(out.contains("--max-steps needs a number"), "{}", out);
+}
@@ -0,0 +1,131 @@
+extern crate mal;
+
+use std::fs;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use mal::{profile_folded, profile_report, set_profile, Arity, Engine, Interpreter, MalVal};
+
+// An interpreter with spin, a builtin that takes a millisecond, so that
+// the stacks that call it take long enough to show
+fn interpreter(engine: Engine) -> Interpreter {
+    let interp = Interpreter::with_engine(engine);
+    interp.register_fn("spin", Arity::Exactly(0), |_| {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(1) {}
+        Ok(MalVal::Nil)
+    });
+    interp
+        .eval_str(
+            "(def! fib (fn* [n] (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2))))))
+             (def! count-down (fn* [n] (if (> n 0) (do (spin) (count-down (- n 1))) :done)))
+             (def! deep (fn* [n] (if (> n 0) (+ 1 (deep (- n 1))) (do (spin) 0))))",
+        )
+        .unwrap();
+    interp
+}
+
+fn profile(interp: &Interpreter, src: &str) {
+    set_profile(true);
+    interp.eval_str(src).unwrap();
+    set_profile(false);
+}
+
+// The calls of name in the report, which may be among other output
+fn calls(report: &str, name: &str) -> u64 {
+    report
+        .lines()
+        .map(|l| l.split_whitespace().collect::<Vec<_>>())
+        .find(|cols| cols.len() == 4 && cols[3] == name)
+        .unwrap_or_else(|| panic!("no {} in\n{}", name, report))[0]
+        .parse()
+        .unwrap()
+}
+
+#[test]
+fn recursive_calls_are_each_counted() {
+    for engine in [Engine::Tree, Engine::Bytecode] {
+        let interp = interpreter(engine);
+        profile(&interp, "(fib 10)");
+        let report = profile_report();
+        assert_eq!(calls(&report, "fib"), 177, "{}", report);
+        assert_eq!(calls(&report, "+"), 88, "{}", report);
+    }
+}
+
+#[test]
+fn tail_calls_replace_the_call() {
+    for engine in [Engine::Tree, Engine::Bytecode] {
+        let interp = interpreter(engine);
+        profile(&interp, "(count-down 3)");
+        let report = profile_report();
+        assert_eq!(calls(&report, "count-down"), 4, "{}", report);
+        assert_eq!(calls(&report, "spin"), 3, "{}", report);
+        // each spin is called from a count-down that replaced the last
+        let folded = profile_folded();
+        let spins: Vec<&str> = folded.lines().filter(|l| l.contains("spin")).collect();
+        assert_eq!(spins.len(), 1, "{}", folded);
+        assert!(spins[0].starts_with("count-down;spin "), "{}", folded);
+    }
+}
+
+#[test]
+fn folded_stacks() {
+    for engine in [Engine::Tree, Engine::Bytecode] {
+        let interp = interpreter(engine);
+        profile(&interp, "(deep 2)");
+        let folded = profile_folded();
+        let stacks: Vec<(&str, u128)> = folded
+            .lines()
+            .map(|l| {
+                let (stack, us) = l.rsplit_once(' ').unwrap();
+                (stack, us.parse().unwrap())
+            })
+            .collect();
+        assert!(
+            stacks
+                .iter()
+                .any(|&(s, us)| s == "deep;deep;deep;spin" && us >= 1000),
+            "{}",
+            folded
+        );
+        // the quick ones, such as deep;+, show no time and are left out
+        assert!(stacks.iter().all(|&(_, us)| us > 0), "{}", folded);
+        assert!(
+            stacks.iter().all(|&(s, _)| s.starts_with("deep")),
+            "{}",
+            folded
+        );
+    }
+}
+
+#[test]
+fn command_line_flag() {
+    let dir = std::env::temp_dir().join(format!("mal-profile-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(
+        dir.join("fib.mal"),
+        "(def! fib (fn* [n] (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))) (fib 15)",
+    )
+    .unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_stepA_mal"))
+        .current_dir(&dir)
+        .args(["--profile", "fib.mal"])
+        .output()
+        .unwrap();
+    assert_eq!(out.status.code(), Some(0));
+    let report = String::from_utf8_lossy(&out.stderr);
+    assert_eq!(calls(&report, "fib"), 1973, "{}", report);
+    assert!(
+        report.contains("folded stacks written to mal-profile.folded"),
+        "{}",
+        report
+    );
+    let folded = fs::read_to_string(dir.join("mal-profile.folded")).unwrap();
+    assert!(
+        folded.lines().any(|l| l.starts_with("load-file;fib;fib ")),
+        "{}",
+        folded
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,136 @@
+extern crate mal;
+
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use common::eval;
+use mal::limits::is_exceeded;
+use mal::types::format_error;
+use mal::{Engine, Interpreter, Output, Sandbox};
+
+// A directory for the test with allowed/ and secret/ in it, and a file
+// in each
+fn dirs(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("mal-sandbox-{}-{}", std::process::id(), name));
+    for dir in ["allowed", "secret"].iter() {
+        fs::create_dir_all(root.join(dir)).unwrap();
+        fs::write(
+            root.join(dir).join("file.mal"),
+            format!("(def! from \"{}\")", dir),
+        )
+        .unwrap();
+    }
+    root
+}
+
+fn sandboxed(root: &Path, output: Output) -> Interpreter {
+    Interpreter::sandboxed(
+        Engine::Tree,
+        Sandbox {
+            read_dirs: vec![root.join("allowed")],
+            output,
+            ..Sandbox::default()
+        },
+    )
+}
+
+fn refused(interp: &Interpreter, path: &Path) {
+    let path = path.to_str().unwrap();
+    let expected = format!("Error: {}: not readable in the sandbox", path);
+    assert_eq!(eval(interp, &format!("(slurp \"{}\")", path)), expected);
+    assert_eq!(eval(interp, &format!("(load-file \"{}\")", path)), expected);
+}
+
+#[test]
+fn reads_only_under_read_dirs() {
+    let root = dirs("reads");
+    let interp = sandboxed(&root, Output::Capture);
+    let allowed = root.join("allowed").join("file.mal");
+    let src = format!("(slurp \"{}\")", allowed.display());
+    assert_eq!(eval(&interp, &src), "\"(def! from \\\"allowed\\\")\"");
+    let src = format!("(do (load-file \"{}\") from)", allowed.display());
+    assert_eq!(eval(&interp, &src), "\"allowed\"");
+    refused(&interp, &root.join("secret").join("file.mal"));
+}
+
+#[test]
+fn dot_dot_does_not_escape() {
+    let root = dirs("dotdot");
+    let interp = sandboxed(&root, Output::Capture);
+    let path = root
+        .join("allowed")
+        .join("..")
+        .join("secret")
+        .join("file.mal");
+    refused(&interp, &path);
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinks_do_not_escape() {
+    let root = dirs("symlink");
+    let interp = sandboxed(&root, Output::Capture);
+    let link = root.join("allowed").join("link.mal");
+    let _ = fs::remove_file(&link);
+    std::os::unix::fs::symlink(root.join("secret").join("file.mal"), &link).unwrap();
+    refused(&interp, &link);
+    let dir_link = root.join("allowed").join("dir");
+    let _ = fs::remove_file(&dir_link);
+    std::os::unix::fs::symlink(root.join("secret"), &dir_link).unwrap();
+    refused(&interp, &dir_link.join("file.mal"));
+}
+
+#[test]
+fn no_terminal() {
+    let root = dirs("terminal");
+    let interp = sandboxed(&root, Output::Capture);
+    for name in ["readline", "set-trace!", "break"].iter() {
+        let src = format!("({})", name);
+        assert_eq!(eval(&interp, &src), format!("Error: '{}' not found", name));
+    }
+    // those of other interpreters are still there
+    assert_eq!(eval(&Interpreter::new(), "(set-trace! false)"), "nil");
+}
+
+#[test]
+fn capture() {
+    let root = dirs("capture");
+    let interp = sandboxed(&root, Output::Capture);
+    eval(&interp, "(prn \"a\" 1) (println \"b\" [2])");
+    assert_eq!(interp.take_output(), "\"a\" 1\nb [2]\n");
+    assert_eq!(interp.take_output(), "");
+    eval(&interp, "(def! DEBUG-EVAL true) (+ 1 2)");
+    assert!(interp.take_output().contains("EVAL: (+ 1 2)\n"));
+}
+
+#[test]
+fn capture_is_bounded() {
+    let root = dirs("bounded");
+    let interp = Interpreter::sandboxed(
+        Engine::Tree,
+        Sandbox {
+            read_dirs: vec![root.join("allowed")],
+            max_output: 10,
+            ..Sandbox::default()
+        },
+    );
+    assert_eq!(eval(&interp, "(println \"123456789\")"), "nil");
+    let err = interp.eval_str("(println \"x\")").unwrap_err();
+    assert!(is_exceeded(&err), "{}", format_error(err));
+    assert_eq!(interp.take_output(), "123456789\n");
+    assert_eq!(eval(&interp, "(println \"x\")"), "nil");
+    assert_eq!(interp.take_output(), "x\n");
+}
+
+#[test]
+fn discard() {
+    let root = dirs("discard");
+    let interp = sandboxed(&root, Output::Discard);
+    eval(
+        &interp,
+        "(def! DEBUG-EVAL true) (prn \"a\") (println \"b\")",
+    );
+    assert_eq!(interp.take_output(), "");
+}
@@ -0,0 +1,75 @@
+extern crate mal;
+extern crate serde_json;
+
+use std::collections::BTreeMap;
+
+use mal::serde_mal::{from_value, to_value};
+use mal::types::format_error;
+use mal::{Interpreter, MalVal};
+
+fn read(src: &str) -> MalVal {
+    Interpreter::new().eval_str(src).unwrap()
+}
+
+// The value read from src, written as JSON and read back
+fn through_json(src: &str) -> (String, String) {
+    let json = serde_json::to_string(&read(src)).unwrap();
+    let back: MalVal = serde_json::from_str(&json).unwrap();
+    (json, back.pr_str(true))
+}
+
+#[test]
+fn json_round_trip() {
+    for src in [
+        "nil",
+        "true",
+        "-12",
+        "\"a \\\"b\\\"\"",
+        "[1 [2 nil] {\"x\" [true]}]",
+        "{\"a\" {\"b\" []}}",
+    ]
+    .iter()
+    {
+        let (_, back) = through_json(src);
+        assert_eq!(back, read(src).pr_str(true), "{}", src);
+    }
+}
+
+#[test]
+fn json_loses_what_it_has_no_form_for() {
+    assert_eq!(
+        through_json(":a"),
+        ("\"a\"".to_string(), "\"a\"".to_string())
+    );
+    assert_eq!(through_json("'(1 2)").1, "[1 2]");
+    assert_eq!(through_json("{:a 1}").1, "{\"a\" 1}");
+    assert_eq!(
+        through_json("(sorted-map \"b\" 1 \"a\" 2)"),
+        (
+            "{\"a\":2,\"b\":1}".to_string(),
+            "{\"a\" 2 \"b\" 1}".to_string()
+        )
+    );
+    assert_eq!(through_json("(sorted-set 3 1 2)").1, "[1 2 3]");
+    assert_eq!(through_json("(sorted-map 1 2)").1, "{\"1\" 2}");
+    assert_eq!(through_json("{1 2}").1, "{\"1\" 2}");
+    let err = serde_json::to_string(&read("(sorted-map [1] 2)")).unwrap_err();
+    assert!(err.to_string().contains("key must be a string"), "{}", err);
+    let err = serde_json::to_string(&read("(atom 1)")).unwrap_err();
+    assert_eq!(err.to_string(), "cannot serialize (atom 1)");
+}
+
+#[test]
+fn rust_values_round_trip() {
+    let mut m: BTreeMap<String, Vec<Option<i64>>> = BTreeMap::new();
+    m.insert("a".to_string(), vec![Some(1), None]);
+    let v = to_value(&m).unwrap();
+    assert_eq!(v.pr_str(true), "{\"a\" [1 nil]}");
+    let back: BTreeMap<String, Vec<Option<i64>>> = from_value(&v).unwrap();
+    assert_eq!(back, m);
+    // keywords and strings both become Rust strings
+    let names: Vec<String> = from_value(&read("[:a \"b\"]")).unwrap();
+    assert_eq!(names, ["a", "b"]);
+    let err = from_value::<Vec<i64>>(&read("[1 \"2\"]")).unwrap_err();
+    assert!(format_error(err).contains("invalid type"));
+}
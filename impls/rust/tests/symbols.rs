@@ -0,0 +1,62 @@
+extern crate mal;
+
+mod common;
+
+use common::eval;
+use mal::symbol::{intern, lookup, try_intern, try_intern_data, MAX_SYMBOLS};
+use mal::Interpreter;
+
+// The only test in this binary, as the table is for the whole process
+#[test]
+fn the_table_is_bounded() {
+    let interp = Interpreter::new();
+    assert_eq!(eval(&interp, "(def! known (symbol \"known\"))"), "known");
+    // keywords made from data leave the table alone
+    eval(
+        &interp,
+        "(json-parse \"{\\\"from-json\\\": 1}\" {:keywordize true})",
+    );
+    eval(&interp, "(edn-read-string \"{:from-edn 1}\")");
+    eval(&interp, "(keyword \"from-keyword\")");
+    for name in ["from-json", "from-edn", "from-keyword"].iter() {
+        assert!(lookup(name).is_none(), "{}", name);
+    }
+    // symbols in data may fill half of it
+    let mut added = 0;
+    while try_intern_data(&format!("data-{}", added)).is_some() {
+        added += 1;
+    }
+    assert!(added <= MAX_SYMBOLS / 2, "{}", added);
+    assert_eq!(
+        eval(&interp, "(edn-read-string \"brand-new\")"),
+        "Error: too many symbols"
+    );
+    assert_eq!(eval(&interp, "(read-string \"from-code\")"), "from-code");
+    // and code the rest
+    while try_intern(&format!("code-{}", added)).is_some() {
+        added += 1;
+    }
+    assert!(added < MAX_SYMBOLS, "{}", added);
+    for src in ["(symbol \"brand-new\")", "(read-string \"brand-new\")"].iter() {
+        assert_eq!(eval(&interp, src), "Error: too many symbols", "{}", src);
+    }
+    // keywords are still fine, if no longer interned
+    for (src, res) in [
+        ("(keyword \"fresh\")", ":fresh"),
+        ("(read-string \":fresh\")", ":fresh"),
+        ("(edn-read-string \"[:fresh]\")", "[:fresh]"),
+        (
+            "(keys (json-parse \"{\\\"fresh\\\": 1}\" {:keywordize true}))",
+            "(:fresh)",
+        ),
+        ("(= :fresh (keyword \"fresh\"))", "true"),
+    ]
+    .iter()
+    {
+        assert_eq!(eval(&interp, src), *res, "{}", src);
+    }
+    // names there already are still fine, and the interpreter itself
+    // may still add its own
+    assert_eq!(eval(&interp, "(= known (symbol \"known\"))"), "true");
+    assert_eq!(intern("brand-new").name(), "brand-new");
+}
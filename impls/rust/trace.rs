@@ -0,0 +1,116 @@
+use std::cell::Cell;
+
+use crate::debug;
+use crate::env::{env_get, Env};
+use crate::sandbox;
+use crate::symbol::{intern, Symbol};
+use crate::types::MalVal::{Bool, Nil};
+use crate::types::{MalErr, MalRet, MalVal};
+
+// Tracing of evaluation, for the evaluators to call. While it is off
+// they only test a flag. When on, each top-level form and function
+// call is printed to stderr as it starts, indented by depth, followed
+// by the result it produced. The debugger steps through the same
+// calls.
+//
+// DEBUG-EVAL is supported as in the earlier steps, but it is only
+// looked up once the compiler has seen something bind it.
+//
+// The state is per thread, like the values it prints. An Interpreter
+// swaps its own setting in while it evaluates.
+
+thread_local! {
+    static TRACE: Cell<bool> = const { Cell::new(false) };
+    static DEBUG_EVAL_BOUND: Cell<bool> = const { Cell::new(false) };
+    // either of the above
+    static ACTIVE: Cell<bool> = const { Cell::new(false) };
+    // tracing or stepping in the debugger
+    static HOOKED: Cell<bool> = const { Cell::new(false) };
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+lazy_static! {
+    static ref DEBUG_EVAL: Symbol = intern("DEBUG-EVAL");
+}
+
+pub fn update_active() {
+    let trace = TRACE.with(|t| t.get());
+    let on = trace || DEBUG_EVAL_BOUND.with(|b| b.get());
+    ACTIVE.with(|a| a.set(on));
+    HOOKED.with(|h| h.set(trace || debug::stepping()));
+}
+
+pub fn set_trace(on: bool) {
+    TRACE.with(|t| t.set(on));
+    DEPTH.with(|d| d.set(0));
+    update_active();
+}
+
+// Sets whether tracing is on, as set_trace does if it changes, and
+// returns whether it was
+pub fn swap_trace(on: bool) -> bool {
+    let was = TRACE.with(|t| t.get());
+    if was != on {
+        set_trace(on);
+    }
+    was
+}
+
+// Whether calls have to be reported to enter and leave
+pub fn hooked() -> bool {
+    HOOKED.with(|h| h.get())
+}
+
+pub fn depth() -> usize {
+    DEPTH.with(|d| d.get())
+}
+
+// Whether either tracing or DEBUG-EVAL may need to print something
+pub fn active() -> bool {
+    ACTIVE.with(|a| a.get())
+}
+
+// Called for each name a form binds
+pub fn note_binding(name: Symbol) {
+    if name == *DEBUG_EVAL {
+        DEBUG_EVAL_BOUND.with(|b| b.set(true));
+        update_active();
+    }
+}
+
+pub fn debug_eval(form: &MalVal, env: &Env) {
+    if !DEBUG_EVAL_BOUND.with(|b| b.get()) {
+        return;
+    }
+    match env_get(env, *DEBUG_EVAL) {
+        None | Some(Bool(false)) | Some(Nil) => (),
+        _ => sandbox::print_line(&format!("EVAL: {}", form.pr_str(true))),
+    }
+}
+
+fn indent() -> String {
+    "  ".repeat(DEPTH.with(|d| d.get()))
+}
+
+// Called as a call starts, with the env it is made in. Only an error
+// from the debugger stops the call, and then leave is not called.
+pub fn enter(form: &MalVal, env: &Env) -> Result<(), MalErr> {
+    if debug::stepping() {
+        debug::step(form, env, depth())?;
+    }
+    if TRACE.with(|t| t.get()) {
+        eprintln!("{}{}", indent(), form.pr_str(true));
+    }
+    DEPTH.with(|d| d.set(d.get() + 1));
+    Ok(())
+}
+
+pub fn leave(res: &MalRet) {
+    DEPTH.with(|d| d.set(d.get().saturating_sub(1)));
+    if TRACE.with(|t| t.get()) {
+        match res {
+            Ok(v) => eprintln!("{}=> {}", indent(), v.pr_str(true)),
+            Err(e) => eprintln!("{}=> Error: {}", indent(), e),
+        }
+    }
+}
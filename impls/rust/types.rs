@@ -1,12 +1,22 @@
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::error::Error;
+use std::fmt;
+use std::rc::{Rc, Weak};
 //use std::collections::HashMap;
 use fnv::FnvHashMap;
 use itertools::Itertools;
 
-use crate::env::{env_bind, Env};
+use crate::env::{env_bind, env_counts, Env};
+use crate::limits::check_depth;
+use crate::profile;
+use crate::sorted::SortedTree;
+use crate::symbol::{lookup, try_intern, try_intern_data, Keyword, Symbol};
 use crate::types::MalErr::{ErrMalVal, ErrString};
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
+use crate::types::MalVal::{
+    Atom, Bool, Func, Hash, Int, Kw, List, MalFunc, Nil, SortedMap, SortedSet, Str, Sym, Vector,
+};
 
 #[derive(Clone)]
 pub enum MalVal {
@@ -15,18 +25,25 @@ pub enum MalVal {
     Int(i64),
     //Float(f64),
     Str(String),
-    Sym(String),
+    Sym(Symbol),
+    // a keyword, by its name without the ':'
+    Kw(Keyword),
     List(Rc<Vec<MalVal>>, Rc<MalVal>),
     Vector(Rc<Vec<MalVal>>, Rc<MalVal>),
     Hash(Rc<FnvHashMap<String, MalVal>>, Rc<MalVal>),
-    Func(fn(MalArgs) -> MalRet, Rc<MalVal>),
+    SortedMap(Rc<SortedTree>, Rc<MalVal>),
+    SortedSet(Rc<SortedTree>, Rc<MalVal>),
+    Func(Builtin, Rc<MalVal>),
     MalFunc {
-        eval: fn(ast: &MalVal, env: &Env) -> MalRet,
+        eval: EvalFn,
         ast: Rc<MalVal>,
         env: Env,
         params: Rc<MalVal>,
         is_macro: bool,
         meta: Rc<MalVal>,
+        // the body compiled ahead of time, if the evaluator does that
+        code: Option<Rc<dyn Compiled>>,
+        counted: Rc<FnCount>,
     },
     Atom(Rc<RefCell<MalVal>>),
 }
@@ -38,25 +55,126 @@ pub enum MalErr {
 
 pub type MalArgs = Vec<MalVal>;
 pub type MalRet = Result<MalVal, MalErr>;
+pub type EvalFn = fn(ast: &MalVal, env: &Env) -> MalRet;
+
+#[derive(Clone, Copy)]
+pub enum Arity {
+    Exactly(usize),
+    AtLeast(usize),
+    Between(usize, usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, n: usize) -> bool {
+        match *self {
+            Arity::Exactly(m) => n == m,
+            Arity::AtLeast(m) => n >= m,
+            Arity::Between(lo, hi) => lo <= n && n <= hi,
+        }
+    }
+}
+
+// What a builtin called from eval gets to see of the interpreter: the
+// environment at the call site and the evaluator itself.
+pub struct Context<'a> {
+    pub env: &'a Env,
+    pub eval: EvalFn,
+}
+
+// A function compiled by an evaluator that works that way rather than
+// walking `ast`. It binds its own args, as the evaluator decides how
+// its env is laid out. The evaluator can get its own representation
+// back through as_any, e.g. to make tail calls without recursing.
+pub trait Compiled {
+    // env is the one the function closed over
+    fn call(self: Rc<Self>, env: &Env, args: MalArgs) -> MalRet;
+    fn as_any(self: Rc<Self>) -> Rc<dyn Any>;
+    // the symbol it was first def!'d to, for profiles
+    fn name(&self) -> Option<Symbol>;
+    fn set_name(&self, name: Symbol);
+}
+
+pub type PlainFn = dyn Fn(MalArgs) -> MalRet;
+pub type ContextFn = dyn Fn(MalArgs, &Context) -> MalRet;
+
+#[derive(Clone)]
+pub enum Native {
+    Plain(Rc<PlainFn>),
+    WithContext(Rc<ContextFn>),
+}
+
+// A function implemented in Rust, possibly a closure holding its own
+// state. The argument count is checked against the arity before f is
+// called, so f may index its arguments freely.
+#[derive(Clone)]
+pub struct Builtin {
+    pub name: String,
+    pub arity: Arity,
+    pub f: Native,
+}
+
+impl Builtin {
+    pub fn call(&self, args: MalArgs, ctx: Option<&Context>) -> MalRet {
+        if !self.arity.accepts(args.len()) {
+            return Err(ErrString(format!(
+                "wrong number of args ({}) passed to {}",
+                args.len(),
+                self.name
+            )));
+        }
+        match (&self.f, ctx) {
+            (Native::Plain(f), _) => f(args),
+            (Native::WithContext(f), Some(ctx)) => f(args, ctx),
+            (Native::WithContext(f), None) => match FALLBACK.with(|c| c.borrow().clone()) {
+                Some((env, eval)) => f(args, &Context { env: &env, eval }),
+                None => Err(ErrString(format!(
+                    "{} can only be called from the evaluator",
+                    self.name
+                ))),
+            },
+        }
+    }
+}
+
+thread_local! {
+    // what builtins that take a Context get when called without one,
+    // e.g. by sort-by or swap!
+    static FALLBACK: RefCell<Option<(Env, EvalFn)>> = const { RefCell::new(None) };
+}
+
+// Runs f with env and eval, those of the REPL of the interpreter
+// running, as the Context for builtins called without one
+pub fn with_fallback_context<T>(env: &Env, eval: EvalFn, f: impl FnOnce() -> T) -> T {
+    let outer = FALLBACK.with(|c| c.replace(Some((env.clone(), eval))));
+    let res = f();
+    FALLBACK.with(|c| *c.borrow_mut() = outer);
+    res
+}
 
 // type utility macros
 
+#[macro_export]
 macro_rules! list {
   ($seq:expr) => {{
+    $crate::types::count_collection();
     List(Rc::new($seq),Rc::new(Nil))
   }};
   [$($args:expr),*] => {{
     let v: Vec<MalVal> = vec![$($args),*];
+    $crate::types::count_collection();
     List(Rc::new(v),Rc::new(Nil))
   }}
 }
 
+#[macro_export]
 macro_rules! vector {
   ($seq:expr) => {{
+    $crate::types::count_collection();
     Vector(Rc::new($seq),Rc::new(Nil))
   }};
   [$($args:expr),*] => {{
     let v: Vec<MalVal> = vec![$($args),*];
+    $crate::types::count_collection();
     Vector(Rc::new(v),Rc::new(Nil))
   }}
 }
@@ -74,15 +192,216 @@ pub fn format_error(e: MalErr) -> String {
     }
 }
 
+impl fmt::Display for MalErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrString(s) => write!(f, "{}", s),
+            ErrMalVal(mv) => write!(f, "{}", mv.pr_str(true)),
+        }
+    }
+}
+
+impl fmt::Debug for MalErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrString(s) => write!(f, "ErrString({:?})", s),
+            ErrMalVal(mv) => write!(f, "ErrMalVal({:?})", mv),
+        }
+    }
+}
+
+impl Error for MalErr {}
+
+impl fmt::Debug for MalVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.pr_str(true))
+    }
+}
+
 pub fn atom(mv: &MalVal) -> MalVal {
-    Atom(Rc::new(RefCell::new(mv.clone())))
+    let a = Rc::new(RefCell::new(mv.clone()));
+    ATOMS.with(|r| r.borrow_mut().note(&a));
+    Atom(a)
+}
+
+// Counters for runtime-stats. Envs count themselves, in env.rs, and
+// functions with their FnCount. The atoms that are alive are found
+// from weak references, which are pruned as they grow, so a freed
+// one's memory is only given back at the next pruning. The evaluators
+// count the forms they evaluate and the calls they make, and how
+// deeply calls nest; tail calls do not nest.
+
+thread_local! {
+    // functions alive and made
+    static FNS: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+    static ATOMS: RefCell<Live<RefCell<MalVal>>> = RefCell::new(Live::default());
+    static READS: Cell<usize> = const { Cell::new(0) };
+    static EVALS: Cell<usize> = const { Cell::new(0) };
+    static CALLS: Cell<usize> = const { Cell::new(0) };
+    static COLLECTIONS: Cell<usize> = const { Cell::new(0) };
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+    static PEAK_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+const MIN_PRUNE: usize = 1024;
+
+struct Live<T> {
+    refs: Vec<Weak<T>>,
+    // pruned when refs gets this long
+    limit: usize,
+    made: usize,
+}
+
+impl<T> Default for Live<T> {
+    fn default() -> Self {
+        Live {
+            refs: vec![],
+            limit: MIN_PRUNE,
+            made: 0,
+        }
+    }
+}
+
+impl<T> Live<T> {
+    fn note(&mut self, rc: &Rc<T>) {
+        self.made += 1;
+        if self.refs.len() >= self.limit {
+            self.count();
+        }
+        self.refs.push(Rc::downgrade(rc));
+    }
+
+    fn count(&mut self) -> usize {
+        self.refs.retain(|w| w.strong_count() > 0);
+        self.limit = MIN_PRUNE.max(self.refs.len() * 2);
+        self.refs.len()
+    }
+}
+
+// Each function made by fn*, defmacro! or with-meta has one of these,
+// which its copies share, to count it while it is alive.
+pub struct FnCount(());
+
+impl FnCount {
+    pub fn new() -> Rc<FnCount> {
+        FNS.with(|n| {
+            let (live, made) = n.get();
+            n.set((live + 1, made + 1))
+        });
+        Rc::new(FnCount(()))
+    }
+}
+
+impl Drop for FnCount {
+    fn drop(&mut self) {
+        // there is nothing left to count once the thread is ending
+        let _ = FNS.try_with(|n| {
+            let (live, made) = n.get();
+            n.set((live - 1, made))
+        });
+    }
+}
+
+fn bump(c: &'static std::thread::LocalKey<Cell<usize>>) {
+    c.with(|n| n.set(n.get() + 1))
+}
+
+// Called as a list, vector, map or set is made, by list!, vector!,
+// new_hash and the sorted collections' constructors
+pub fn count_collection() {
+    bump(&COLLECTIONS)
+}
+
+pub fn count_read() {
+    bump(&READS)
+}
+
+pub fn count_eval() {
+    bump(&EVALS)
+}
+
+pub fn count_call() {
+    bump(&CALLS)
+}
+
+// Called as a call that is not a tail call starts, and leave_call as
+// it returns or fails. Fails instead if it would go over the depth
+// limit.
+pub fn enter_call() -> Result<(), MalErr> {
+    let depth = DEPTH.with(|d| d.get()) + 1;
+    check_depth(depth)?;
+    DEPTH.with(|d| d.set(depth));
+    PEAK_DEPTH.with(|p| p.set(p.get().max(depth)));
+    Ok(())
+}
+
+pub fn leave_call() {
+    DEPTH.with(|d| d.set(d.get().saturating_sub(1)))
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RuntimeStats {
+    pub live_envs: usize,
+    pub live_fns: usize,
+    pub live_atoms: usize,
+    // made so far
+    pub envs_made: usize,
+    pub fns_made: usize,
+    pub atoms_made: usize,
+    pub collections_made: usize,
+    // calls to read_str
+    pub reads: usize,
+    // top-level forms evaluated, including by eval and load-file
+    pub evals: usize,
+    pub calls: usize,
+    pub peak_depth: usize,
+}
+
+impl RuntimeStats {
+    pub fn entries(&self) -> [(&'static str, usize); 11] {
+        [
+            ("live-envs", self.live_envs),
+            ("live-fns", self.live_fns),
+            ("live-atoms", self.live_atoms),
+            ("envs-made", self.envs_made),
+            ("fns-made", self.fns_made),
+            ("atoms-made", self.atoms_made),
+            ("collections-made", self.collections_made),
+            ("reads", self.reads),
+            ("evals", self.evals),
+            ("calls", self.calls),
+            ("peak-depth", self.peak_depth),
+        ]
+    }
+}
+
+pub fn runtime_stats() -> RuntimeStats {
+    let (live_envs, envs_made) = env_counts();
+    let (live_fns, fns_made) = FNS.with(|n| n.get());
+    let (live_atoms, atoms_made) = ATOMS.with(|r| {
+        let mut r = r.borrow_mut();
+        (r.count(), r.made)
+    });
+    RuntimeStats {
+        live_envs,
+        live_fns,
+        live_atoms,
+        envs_made,
+        fns_made,
+        atoms_made,
+        collections_made: COLLECTIONS.with(|n| n.get()),
+        reads: READS.with(|n| n.get()),
+        evals: EVALS.with(|n| n.get()),
+        calls: CALLS.with(|n| n.get()),
+        peak_depth: PEAK_DEPTH.with(|n| n.get()),
+    }
 }
 
 impl MalVal {
     pub fn keyword(&self) -> MalRet {
         match self {
-            Str(s) if s.starts_with('\u{29e}') => Ok(Str(s.to_string())),
-            Str(s) => Ok(Str(format!("\u{29e}{}", s))),
+            Kw(_) => Ok(self.clone()),
+            Str(s) => Ok(keyword(s)),
             _ => error("invalid type for keyword"),
         }
     }
@@ -90,6 +409,7 @@ impl MalVal {
     pub fn empty_q(&self) -> MalRet {
         match self {
             List(l, _) | Vector(l, _) => Ok(Bool(l.len() == 0)),
+            SortedMap(t, _) | SortedSet(t, _) => Ok(Bool(t.is_empty())),
             Nil => Ok(Bool(true)),
             _ => error("invalid type for empty?"),
         }
@@ -98,30 +418,48 @@ impl MalVal {
     pub fn count(&self) -> MalRet {
         match self {
             List(l, _) | Vector(l, _) => Ok(Int(l.len() as i64)),
+            SortedMap(t, _) | SortedSet(t, _) => Ok(Int(t.len() as i64)),
             Nil => Ok(Int(0)),
             _ => error("invalid type for count"),
         }
     }
 
     pub fn apply(&self, args: MalArgs) -> MalRet {
+        self.apply_ctx(args, None)
+    }
+
+    // Builtins that take a Context get ctx passed through; user
+    // functions carry their own evaluator and environment.
+    pub fn apply_ctx(&self, args: MalArgs, ctx: Option<&Context>) -> MalRet {
+        if profile::enabled() {
+            profile::enter(self);
+            let res = self.call(args, ctx);
+            profile::leave();
+            return res;
+        }
+        self.call(args, ctx)
+    }
+
+    fn call(&self, args: MalArgs, ctx: Option<&Context>) -> MalRet {
         match self {
-            Func(f, _) => f(args),
+            Func(b, _) => b.call(args, ctx),
             MalFunc {
                 eval,
                 ref ast,
                 env,
                 ref params,
+                ref code,
                 ..
-            } => {
-                let fn_env = &env_bind(Some(env.clone()), params, args)?;
-                eval(ast, fn_env)
-            }
+            } => match code {
+                Some(code) => code.clone().call(env, args),
+                None => eval(ast, &env_bind(Some(env.clone()), params, args)?),
+            },
             _ => error("attempt to call non-function"),
         }
     }
 
     pub fn keyword_q(&self) -> bool {
-        matches!(self, Str(s) if s.starts_with('\u{29e}'))
+        matches!(self, Kw(_))
     }
 
     pub fn deref(&self) -> MalRet {
@@ -157,6 +495,7 @@ impl MalVal {
     pub fn get_meta(&self) -> MalRet {
         match self {
             List(_, meta) | Vector(_, meta) | Hash(_, meta) => Ok((**meta).clone()),
+            SortedMap(_, meta) | SortedSet(_, meta) => Ok((**meta).clone()),
             Func(_, meta) => Ok((**meta).clone()),
             MalFunc { meta, .. } => Ok((**meta).clone()),
             _ => error("meta not supported by type"),
@@ -168,14 +507,77 @@ impl MalVal {
             List(_, ref mut meta)
             | Vector(_, ref mut meta)
             | Hash(_, ref mut meta)
-            | Func(_, ref mut meta)
-            | MalFunc { ref mut meta, .. } => {
+            | SortedMap(_, ref mut meta)
+            | SortedSet(_, ref mut meta)
+            | Func(_, ref mut meta) => {
+                *meta = Rc::new(new_meta.clone());
+            }
+            MalFunc {
+                ref mut meta,
+                ref mut counted,
+                ..
+            } => {
                 *meta = Rc::new(new_meta.clone());
+                *counted = FnCount::new();
             }
             _ => return error("with-meta not supported by type"),
         };
         Ok(self.clone())
     }
+
+    // Default ordering used by compare, sort and sorted collections:
+    // nil sorts first, sequential collections compare element-wise,
+    // otherwise only values of the same kind can be compared.
+    pub fn compare(&self, other: &MalVal) -> Result<Ordering, MalErr> {
+        match (self, other) {
+            (Nil, Nil) => Ok(Ordering::Equal),
+            (Nil, _) => Ok(Ordering::Less),
+            (_, Nil) => Ok(Ordering::Greater),
+            (Bool(a), Bool(b)) => Ok(a.cmp(b)),
+            (Int(a), Int(b)) => Ok(a.cmp(b)),
+            (Str(a), Str(b)) => Ok(a.cmp(b)),
+            (Sym(a), Sym(b)) => Ok(a.cmp(b)),
+            (Kw(a), Kw(b)) => Ok(a.cmp(b)),
+            (List(a, _) | Vector(a, _), List(b, _) | Vector(b, _)) => {
+                for (x, y) in a.iter().zip(b.iter()) {
+                    match x.compare(y)? {
+                        Ordering::Equal => continue,
+                        ord => return Ok(ord),
+                    }
+                }
+                Ok(a.len().cmp(&b.len()))
+            }
+            _ => Err(ErrString(format!(
+                "cannot compare {} and {}",
+                self.pr_str(true),
+                other.pr_str(true)
+            ))),
+        }
+    }
+}
+
+// A comparator function may return a number (negative, zero or
+// positive) or, like `<`, a boolean meaning "a sorts before b".
+pub fn compare_with(
+    comparator: Option<&MalVal>,
+    a: &MalVal,
+    b: &MalVal,
+) -> Result<Ordering, MalErr> {
+    let f = match comparator {
+        None => return a.compare(b),
+        Some(f) => f,
+    };
+    match f.apply(vec![a.clone(), b.clone()])? {
+        Int(n) => Ok(n.cmp(&0)),
+        Bool(true) => Ok(Ordering::Less),
+        Bool(false) | Nil => match f.apply(vec![b.clone(), a.clone()])? {
+            Bool(false) | Nil => Ok(Ordering::Equal),
+            _ => Ok(Ordering::Greater),
+        },
+        _ => Err(ErrString(
+            "comparator must return a number or boolean".to_string(),
+        )),
+    }
 }
 
 impl PartialEq for MalVal {
@@ -186,19 +588,176 @@ impl PartialEq for MalVal {
             (Int(ref a), Int(ref b)) => a == b,
             (Str(ref a), Str(ref b)) => a == b,
             (Sym(ref a), Sym(ref b)) => a == b,
+            (Kw(ref a), Kw(ref b)) => a == b,
             (List(ref a, _), List(ref b, _))
             | (Vector(ref a, _), Vector(ref b, _))
             | (List(ref a, _), Vector(ref b, _))
             | (Vector(ref a, _), List(ref b, _)) => a == b,
             (Hash(ref a, _), Hash(ref b, _)) => a == b,
+            (SortedMap(ref a, _), SortedMap(ref b, _)) => a.entries() == b.entries(),
+            (SortedSet(ref a, _), SortedSet(ref b, _)) => a.keys() == b.keys(),
+            (Hash(ref h, _), SortedMap(ref t, _)) | (SortedMap(ref t, _), Hash(ref h, _)) => {
+                h.len() == t.len()
+                    && t.entries().iter().all(|(k, v)| match hash_key(k) {
+                        Some(s) => h.get(&s) == Some(v),
+                        None => false,
+                    })
+            }
             (MalFunc { .. }, MalFunc { .. }) => false,
             _ => false,
         }
     }
 }
 
-pub fn func(f: fn(MalArgs) -> MalRet) -> MalVal {
-    Func(f, Rc::new(Nil))
+pub fn builtin<F>(name: &str, arity: Arity, f: F) -> MalVal
+where
+    F: Fn(MalArgs) -> MalRet + 'static,
+{
+    Func(
+        Builtin {
+            name: name.to_string(),
+            arity,
+            f: Native::Plain(Rc::new(f)),
+        },
+        Rc::new(Nil),
+    )
+}
+
+pub fn builtin_ctx<F>(name: &str, arity: Arity, f: F) -> MalVal
+where
+    F: Fn(MalArgs, &Context) -> MalRet + 'static,
+{
+    Func(
+        Builtin {
+            name: name.to_string(),
+            arity,
+            f: Native::WithContext(Rc::new(f)),
+        },
+        Rc::new(Nil),
+    )
+}
+
+// A symbol interned from a name a program read or made, or from one
+// in data it read
+pub fn symbol(name: &str) -> MalRet {
+    interned(try_intern(name))
+}
+
+pub fn data_symbol(name: &str) -> MalRet {
+    interned(try_intern_data(name))
+}
+
+fn interned(s: Option<Symbol>) -> MalRet {
+    s.map(Sym)
+        .ok_or_else(|| ErrString("too many symbols".to_string()))
+}
+
+// A keyword made at run time, which is not interned
+pub fn keyword(name: &str) -> MalVal {
+    Kw(Keyword::from_data(name))
+}
+
+// Hash maps are keyed by strings: strings are their own keys, keywords
+// their names after a '\u{29e}', and any other value that is compared by
+// value an encoding of it after a '\u{29f}'. Equal values encode the
+// same, so that e.g. (1 2) and [1 2] are the same key. Functions and
+// atoms, and what holds them, cannot be keys.
+pub fn hash_key(k: &MalVal) -> Option<String> {
+    match k {
+        Str(s) => Some(s.to_string()),
+        Kw(k) => Some(format!("\u{29e}{}", k)),
+        _ => encode_key(k).map(|e| format!("\u{29f}{}", e)),
+    }
+}
+
+// A letter for the type, then the value, with names and strings after
+// their length so that they can be read back
+fn encode_key(k: &MalVal) -> Option<String> {
+    Some(match k {
+        Nil => "n".to_string(),
+        Bool(b) => if *b { "t" } else { "f" }.to_string(),
+        Int(i) => format!("i{};", i),
+        Str(s) => format!("s{}:{}", s.len(), s),
+        Sym(s) => format!("y{}:{}", s.len(), s),
+        Kw(k) => format!("k{}:{}", k.len(), k),
+        List(l, _) | Vector(l, _) => {
+            let items: Vec<String> = l.iter().map(encode_key).collect::<Option<_>>()?;
+            format!("[{}]", items.concat())
+        }
+        Hash(hm, _) => encode_entries(hm.iter().map(|(k, v)| (key_val(k), v.clone())))?,
+        SortedMap(t, _) => encode_entries(t.entries().into_iter())?,
+        SortedSet(t, _) => {
+            let items: Vec<String> = t.keys().iter().map(encode_key).collect::<Option<_>>()?;
+            format!("#{}}}", items.iter().sorted().join(""))
+        }
+        _ => return None,
+    })
+}
+
+// Entries in the order of their keys' encodings, whichever kind of map
+// they are from
+fn encode_entries(entries: impl Iterator<Item = (MalVal, MalVal)>) -> Option<String> {
+    let pairs: Vec<String> = entries
+        .map(|(k, v)| Some(format!("{}{}", encode_key(&k)?, encode_key(&v)?)))
+        .collect::<Option<_>>()?;
+    Some(format!("{{{}}}", pairs.iter().sorted().join("")))
+}
+
+fn decode_key(s: &mut &str) -> Option<MalVal> {
+    let tag = s.chars().next()?;
+    *s = &s[tag.len_utf8()..];
+    Some(match tag {
+        'n' => Nil,
+        't' => Bool(true),
+        'f' => Bool(false),
+        'i' => {
+            let end = s.find(';')?;
+            let i = s[..end].parse().ok()?;
+            *s = &s[end + 1..];
+            Int(i)
+        }
+        's' | 'y' | 'k' => {
+            let colon = s.find(':')?;
+            let len: usize = s[..colon].parse().ok()?;
+            let name = s.get(colon + 1..colon + 1 + len)?;
+            *s = &s[colon + 1 + len..];
+            match tag {
+                's' => Str(name.to_string()),
+                // it was made from a symbol, so it is interned already
+                'y' => Sym(lookup(name)?),
+                _ => keyword(name),
+            }
+        }
+        '[' => vector!(decode_items(s, ']')?),
+        '{' => hash_map(decode_items(s, '}')?).ok()?,
+        '#' => sorted_set(None, decode_items(s, '}')?).ok()?,
+        _ => return None,
+    })
+}
+
+fn decode_items(s: &mut &str, end: char) -> Option<MalArgs> {
+    let mut items = vec![];
+    while !s.starts_with(end) {
+        items.push(decode_key(s)?);
+    }
+    *s = &s[1..];
+    Some(items)
+}
+
+// The value a hash map key is for. Sequences come back as vectors, and
+// maps as hash maps.
+pub fn key_val(k: &str) -> MalVal {
+    if let Some(name) = k.strip_prefix('\u{29e}') {
+        return keyword(name);
+    }
+    if let Some(mut code) = k.strip_prefix('\u{29f}') {
+        match decode_key(&mut code) {
+            Some(v) if code.is_empty() => return v,
+            // a string that only looks like an encoding
+            _ => (),
+        }
+    }
+    Str(k.to_string())
 }
 
 pub fn _assoc(mut hm: FnvHashMap<String, MalVal>, kvs: MalArgs) -> MalRet {
@@ -206,29 +765,78 @@ pub fn _assoc(mut hm: FnvHashMap<String, MalVal>, kvs: MalArgs) -> MalRet {
         return error("odd number of elements");
     }
     for (k, v) in kvs.iter().tuples() {
-        match k {
-            Str(s) => {
-                hm.insert(s.to_string(), v.clone());
+        match hash_key(k) {
+            Some(s) => {
+                hm.insert(s, v.clone());
             }
-            _ => return error("key is not string"),
+            None => return error(&format!("{} cannot be a map key", k.pr_str(true))),
         }
     }
-    Ok(Hash(Rc::new(hm), Rc::new(Nil)))
+    Ok(new_hash(hm))
 }
 
 pub fn _dissoc(mut hm: FnvHashMap<String, MalVal>, ks: MalArgs) -> MalRet {
     for k in ks {
-        match k {
-            Str(ref s) => {
-                let _ = hm.remove(s);
+        match hash_key(&k) {
+            Some(s) => {
+                let _ = hm.remove(&s);
             }
-            _ => return error("key is not string"),
+            None => return error(&format!("{} cannot be a map key", k.pr_str(true))),
         }
     }
-    Ok(Hash(Rc::new(hm), Rc::new(Nil)))
+    Ok(new_hash(hm))
+}
+
+// A hash map of hm, counted as made
+pub fn new_hash(hm: FnvHashMap<String, MalVal>) -> MalVal {
+    count_collection();
+    Hash(Rc::new(hm), Rc::new(Nil))
 }
 
 pub fn hash_map(kvs: MalArgs) -> MalRet {
     let hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
     _assoc(hm, kvs)
 }
+
+pub fn _sorted_assoc(mut t: SortedTree, kvs: MalArgs) -> MalRet {
+    if kvs.len() % 2 != 0 {
+        return error("odd number of elements");
+    }
+    for (k, v) in kvs.iter().tuples() {
+        t = t.insert(k.clone(), v.clone())?;
+    }
+    count_collection();
+    Ok(SortedMap(Rc::new(t), Rc::new(Nil)))
+}
+
+pub fn _sorted_dissoc(mut t: SortedTree, ks: MalArgs) -> MalRet {
+    for k in ks.iter() {
+        t = t.remove(k)?;
+    }
+    count_collection();
+    Ok(SortedMap(Rc::new(t), Rc::new(Nil)))
+}
+
+pub fn _sorted_conj(mut t: SortedTree, ks: MalArgs) -> MalRet {
+    for k in ks {
+        t = t.insert(k, Nil)?;
+    }
+    count_collection();
+    Ok(SortedSet(Rc::new(t), Rc::new(Nil)))
+}
+
+pub fn _sorted_disj(mut t: SortedTree, ks: MalArgs) -> MalRet {
+    for k in ks.iter() {
+        t = t.remove(k)?;
+    }
+    count_collection();
+    Ok(SortedSet(Rc::new(t), Rc::new(Nil)))
+}
+
+pub fn sorted_map(comparator: Option<MalVal>, kvs: MalArgs) -> MalRet {
+    _sorted_assoc(SortedTree::new(comparator), kvs)
+}
+
+pub fn sorted_set(comparator: Option<MalVal>, ks: MalArgs) -> MalRet {
+    _sorted_conj(SortedTree::new(comparator), ks)
+}
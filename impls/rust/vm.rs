@@ -0,0 +1,439 @@
+use std::any::Any;
+use std::mem;
+use std::rc::Rc;
+
+use crate::bytecode::{lower, Instr, Proto};
+use crate::compile::compile;
+use crate::cover;
+use crate::env::{
+    env_bind_local, env_get, env_get_local, env_new_local, env_set_local, env_sets, Env,
+};
+use crate::eval::{clone_err, exception};
+use crate::gc;
+use crate::limits;
+use crate::profile;
+use crate::symbol::Symbol;
+use crate::trace;
+use crate::types::MalErr::ErrString;
+use crate::types::MalVal::{Bool, Func, List, MalFunc, Nil, Sym, Vector};
+use crate::types::{
+    count_call, count_eval, enter_call, error, leave_call, new_hash, Compiled, Context, FnCount,
+    MalArgs, MalErr, MalRet, MalVal,
+};
+
+// A stack machine for the bytecode in bytecode.rs. Calls from one
+// bytecode function to another push a frame instead of recursing, and
+// tail calls reuse the frame. Builtins and functions from the tree
+// evaluator are called as usual.
+
+impl Compiled for Proto {
+    fn call(self: Rc<Self>, env: &Env, args: MalArgs) -> MalRet {
+        let env = env_bind_local(env.clone(), &self.layout, self.variadic, args)?;
+        enter_call()?;
+        let res = run(self, &env);
+        leave_call();
+        res
+    }
+
+    fn as_any(self: Rc<Self>) -> Rc<dyn Any> {
+        self
+    }
+
+    fn name(&self) -> Option<Symbol> {
+        self.name.get()
+    }
+
+    fn set_name(&self, name: Symbol) {
+        self.name.set(Some(name))
+    }
+}
+
+struct Frame {
+    proto: Rc<Proto>,
+    ip: usize,
+    env: Env,
+    // the envs to go back to at the end of each let* and catch*
+    outer: Vec<Env>,
+    // the height of the stack below this frame
+    base: usize,
+    // whether the call was traced on entry
+    traced: bool,
+    // whether the call has been reported to the profiler
+    profiled: bool,
+    // whether it has been counted as nesting: always unless it is the
+    // first frame, and that frame has not made a tail call
+    entered: bool,
+}
+
+// A try* whose body is running
+struct Handler {
+    frames: usize,
+    stack: usize,
+    outer: usize,
+    env: Env,
+    target: usize,
+}
+
+struct Vm {
+    stack: Vec<MalVal>,
+    frames: Vec<Frame>,
+    handlers: Vec<Handler>,
+}
+
+// Compiles ast to bytecode and runs it. As with eval::eval, the forms
+// of a top-level do are compiled one at a time.
+pub fn eval(ast: &MalVal, env: &Env) -> MalRet {
+    if let List(l, _) = ast {
+        if l.len() > 1 && matches!(l[0], Sym(ref s) if s == "do") {
+            let mut res = Nil;
+            for form in l[1..].iter() {
+                res = eval(form, env)?;
+            }
+            return Ok(res);
+        }
+    }
+    count_eval();
+    let proto = lower(&compile(ast, env));
+    if trace::hooked() {
+        trace::enter(ast, env)?;
+        let res = run(proto, env);
+        trace::leave(&res);
+        return res;
+    }
+    run(proto, env)
+}
+
+pub fn run(proto: Rc<Proto>, env: &Env) -> MalRet {
+    let mut vm = Vm {
+        stack: vec![],
+        frames: vec![Frame {
+            proto,
+            ip: 0,
+            env: env.clone(),
+            outer: vec![],
+            base: 0,
+            traced: false,
+            profiled: false,
+            entered: false,
+        }],
+        handlers: vec![],
+    };
+    loop {
+        match vm.exec() {
+            Ok(v) => return Ok(v),
+            Err(e) => vm.unwind(e)?,
+        }
+    }
+}
+
+impl Vm {
+    // Resumes at the catch* of the innermost try*, if there is one.
+    fn unwind(&mut self, e: MalErr) -> Result<(), MalErr> {
+        let h = self.handlers.pop();
+        let keep = h.as_ref().map_or(0, |h| h.frames);
+        for f in self.frames[keep..].iter().rev() {
+            if f.traced {
+                trace::leave(&Err(clone_err(&e)));
+            }
+            if f.profiled {
+                profile::leave();
+            }
+            if f.entered {
+                leave_call();
+            }
+        }
+        let h = match h {
+            Some(h) => h,
+            None => return Err(e),
+        };
+        self.frames.truncate(h.frames);
+        self.stack.truncate(h.stack);
+        self.stack.push(exception(e));
+        let frame = self.frames.last_mut().unwrap();
+        frame.outer.truncate(h.outer);
+        frame.env = h.env;
+        frame.ip = h.target;
+        Ok(())
+    }
+
+    fn exec(&mut self) -> MalRet {
+        let Vm {
+            stack,
+            frames,
+            handlers,
+        } = self;
+        loop {
+            limits::step()?;
+            let depth = frames.len();
+            let frame = frames.last_mut().unwrap();
+            let instr = frame.proto.code[frame.ip];
+            frame.ip += 1;
+            let (argc, form, tail) = match instr {
+                Instr::Const(k) => {
+                    stack.push(frame.proto.consts[k].clone());
+                    continue;
+                }
+                Instr::Get(n) => {
+                    let s = &frame.proto.names[n];
+                    match env_get(&frame.env, *s) {
+                        Some(v) => stack.push(v),
+                        None => return Err(ErrString(format!("'{}' not found", s))),
+                    }
+                    continue;
+                }
+                Instr::Local(depth, slot) => {
+                    stack.push(env_get_local(&frame.env, depth, slot));
+                    continue;
+                }
+                Instr::Def(n) => {
+                    let v = stack.last().unwrap().clone();
+                    profile::name_fn(&v, frame.proto.names[n]);
+                    env_sets(&frame.env, frame.proto.names[n], v);
+                    continue;
+                }
+                Instr::DefMacro(n) => {
+                    let m = match stack.pop().unwrap() {
+                        MalFunc {
+                            eval,
+                            ast,
+                            env,
+                            params,
+                            code,
+                            ..
+                        } => MalFunc {
+                            eval,
+                            ast,
+                            env,
+                            params,
+                            is_macro: true,
+                            meta: Rc::new(Nil),
+                            code,
+                            counted: FnCount::new(),
+                        },
+                        _ => return error("set_macro on non-function"),
+                    };
+                    profile::name_fn(&m, frame.proto.names[n]);
+                    env_sets(&frame.env, frame.proto.names[n], m.clone());
+                    stack.push(m);
+                    continue;
+                }
+                Instr::SetLocal(slot) => {
+                    let v = stack.pop().unwrap();
+                    env_set_local(&frame.env, slot, v);
+                    continue;
+                }
+                Instr::Pop => {
+                    stack.pop();
+                    continue;
+                }
+                Instr::Jump(t) => {
+                    frame.ip = t;
+                    continue;
+                }
+                Instr::JumpIfFalse(t) => {
+                    if let Bool(false) | Nil = stack.pop().unwrap() {
+                        frame.ip = t;
+                    }
+                    continue;
+                }
+                Instr::Vector(n) => {
+                    let v = stack.split_off(stack.len() - n);
+                    stack.push(vector!(v));
+                    continue;
+                }
+                Instr::Hash(k) => {
+                    let keys = &frame.proto.keys[k];
+                    let vals = stack.split_off(stack.len() - keys.len());
+                    let hm = keys.iter().cloned().zip(vals).collect();
+                    stack.push(new_hash(hm));
+                    continue;
+                }
+                Instr::Closure(k) => {
+                    let p = frame.proto.protos[k].clone();
+                    gc::track(&frame.env);
+                    let f = MalFunc {
+                        eval,
+                        ast: p.ast.clone(),
+                        env: frame.env.clone(),
+                        params: p.params.clone(),
+                        is_macro: false,
+                        meta: Rc::new(Nil),
+                        code: Some(p),
+                        counted: FnCount::new(),
+                    };
+                    stack.push(f);
+                    continue;
+                }
+                Instr::MacroCheck(k, t) => {
+                    if let Some(MalFunc { is_macro: true, .. }) = stack.last() {
+                        // a macro that was not defined yet when the call
+                        // was compiled
+                        let f = stack.pop().unwrap();
+                        let margs = match frame.proto.consts[k] {
+                            List(ref l, _) => l[1..].to_vec(),
+                            _ => vec![],
+                        };
+                        let expanded = f.apply(margs)?;
+                        stack.push(run(lower(&compile(&expanded, &frame.env)), &frame.env)?);
+                        frame.ip = t;
+                    }
+                    continue;
+                }
+                Instr::Call(n, k) => (n, k, false),
+                Instr::TailCall(n, k) => (n, k, true),
+                Instr::EnterScope(k) => {
+                    let inner = env_new_local(Some(frame.env.clone()), &frame.proto.layouts[k]);
+                    frame.outer.push(mem::replace(&mut frame.env, inner));
+                    continue;
+                }
+                Instr::LeaveScope => {
+                    frame.env = frame.outer.pop().unwrap();
+                    continue;
+                }
+                Instr::Try(t) => {
+                    handlers.push(Handler {
+                        frames: depth,
+                        stack: stack.len(),
+                        outer: frame.outer.len(),
+                        env: frame.env.clone(),
+                        target: t,
+                    });
+                    continue;
+                }
+                Instr::EndTry => {
+                    handlers.pop();
+                    continue;
+                }
+                Instr::Raise(k) => return Err(clone_err(&frame.proto.errors[k])),
+                Instr::Trace(k) => {
+                    if trace::active() {
+                        trace::debug_eval(&frame.proto.consts[k], &frame.env);
+                    }
+                    continue;
+                }
+                Instr::Cover(site) => {
+                    cover::hit(site);
+                    continue;
+                }
+                Instr::Return => {
+                    let v = stack.pop().unwrap();
+                    let frame = frames.pop().unwrap();
+                    stack.truncate(frame.base);
+                    if frame.traced {
+                        trace::leave(&Ok(v.clone()));
+                    }
+                    if frame.profiled {
+                        profile::leave();
+                    }
+                    if frame.entered {
+                        leave_call();
+                    }
+                    if frames.is_empty() {
+                        return Ok(v);
+                    }
+                    stack.push(v);
+                    continue;
+                }
+            };
+
+            let args = stack.split_off(stack.len() - argc);
+            let f = stack.pop().unwrap();
+            count_call();
+            // tail calls are reported as part of the call they replace
+            let traced = !tail && trace::hooked();
+            if traced {
+                trace::enter(&frame.proto.consts[form], &frame.env)?;
+            }
+            let res = match f {
+                Func(_, _) => f.apply_ctx(
+                    args,
+                    Some(&Context {
+                        env: &frame.env,
+                        eval,
+                    }),
+                ),
+                MalFunc {
+                    code: Some(ref code),
+                    env: ref menv,
+                    ..
+                } => match code.clone().as_any().downcast::<Proto>() {
+                    Ok(proto) => {
+                        let bound =
+                            env_bind_local(menv.clone(), &proto.layout, proto.variadic, args)
+                                .and_then(|env| {
+                                    if !tail {
+                                        enter_call()?;
+                                    }
+                                    Ok(env)
+                                });
+                        match bound {
+                            Ok(env) => {
+                                let profiled = profile::enabled();
+                                if tail {
+                                    if !frame.entered {
+                                        enter_call()?;
+                                        frame.entered = true;
+                                    }
+                                    if profiled {
+                                        if frame.profiled {
+                                            profile::replace(&f);
+                                        } else {
+                                            profile::enter(&f);
+                                            frame.profiled = true;
+                                        }
+                                    }
+                                    stack.truncate(frame.base);
+                                    frame.proto = proto;
+                                    frame.ip = 0;
+                                    frame.env = env;
+                                    frame.outer.clear();
+                                } else {
+                                    if profiled {
+                                        profile::enter(&f);
+                                    }
+                                    frames.push(Frame {
+                                        proto,
+                                        ip: 0,
+                                        env,
+                                        outer: vec![],
+                                        base: stack.len(),
+                                        traced,
+                                        profiled,
+                                        entered: true,
+                                    });
+                                }
+                                continue;
+                            }
+                            Err(e) => Err(e),
+                        }
+                    }
+                    // compiled by some other evaluator
+                    Err(_) => f.apply(args),
+                },
+                MalFunc { .. } => f.apply(args),
+                _ => error("attempt to call non-function"),
+            };
+            if traced {
+                trace::leave(&res);
+            }
+            let res = res?;
+            if tail {
+                let frame = frames.pop().unwrap();
+                stack.truncate(frame.base);
+                if frame.traced {
+                    trace::leave(&Ok(res.clone()));
+                }
+                if frame.profiled {
+                    profile::leave();
+                }
+                if frame.entered {
+                    leave_call();
+                }
+                if frames.is_empty() {
+                    return Ok(res);
+                }
+            }
+            stack.push(res);
+        }
+    }
+}